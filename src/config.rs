@@ -0,0 +1,696 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::de::{DeserializeSeed, IntoDeserializer, MapAccess, Visitor};
+use serde_derive::Deserialize;
+use snafu::{ensure_whatever, whatever, ResultExt, Whatever};
+use toml::value::{Table, Value};
+use tracing::warn;
+use url::Url;
+
+use crate::dcm::access::{AccessPolicy, AllowedPeer};
+use crate::dcm::tls::TlsConfig;
+use crate::secrets::{self, SecretProvider, SecretsConfig};
+use crate::storage::local_store::LocalStore;
+use crate::storage::object_store::ObjectStore;
+use crate::storage::s3_store::{BucketConfig, S3Store};
+
+/// Prefix of environment variables overriding configuration entries,
+/// e.g. `DCMSHARE_S3_ENDPOINT` overrides `endpoint` in the `[s3]` section
+/// and `DCMSHARE_DICOM__TLS__ENABLED` overrides `enabled` in `[dicom.tls]`.
+const ENV_PREFIX: &str = "DCMSHARE_";
+
+/// Separator of the table names in environment variables naming nested
+/// entries.
+const ENV_PATH_SEPARATOR: &str = "__";
+
+/// Complete gateway configuration as read from `dcmshare.toml`.
+///
+/// The top level sections form the base configuration, tables below
+/// `[profiles.<name>]` have the same layout and override the base when the
+/// profile is selected.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct GatewayConfig {
+    pub dicom: DicomConfig,
+    pub pacs: PacsConfig,
+    pub storage: StorageConfig,
+    pub s3: S3Config,
+    pub matrix: MatrixConfig,
+    pub db: DbConfig,
+    pub retention: RetentionConfig,
+    pub jobs: JobsConfig,
+    pub secrets: SecretsConfig,
+}
+
+/// Store SCP listening for studies to share.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DicomConfig {
+    pub ae_title: String,
+    pub port: u16,
+    pub run_dir: PathBuf,
+    /// Seconds without a new instance after which a study is considered
+    /// complete and shared.
+    pub quiet_period_secs: u64,
+    /// Associations received in parallel, further connections wait.
+    pub max_associations: usize,
+    /// Workers encrypting and uploading received instances.
+    pub upload_workers: usize,
+    /// Upload jobs handed to the workers at once, further due jobs wait in
+    /// the job queue.
+    pub upload_queue_size: usize,
+    /// Calling AE titles allowed to send studies, everyone if empty.
+    pub allowed_peers: Vec<AllowedPeer>,
+    pub tls: TlsConfig,
+}
+
+/// Local PACS nodes received shares are imported into.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct PacsConfig {
+    /// Destination used for rooms without a route.
+    pub default: String,
+    pub destinations: BTreeMap<String, PacsDestination>,
+    /// Matrix room ID to destination name.
+    pub routes: BTreeMap<String, String>,
+    /// Import shares which have no manifest, as posted by gateways from
+    /// before manifests, without verifying them. Otherwise the import is
+    /// retried until the manifest is uploaded.
+    pub import_without_manifest: bool,
+}
+
+/// A PACS node reachable via C-STORE.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct PacsDestination {
+    /// Called AE title
+    pub ae_title: String,
+    pub host: String,
+    pub port: u16,
+    pub calling_ae_title: String,
+    pub max_pdu_length: u32,
+    /// Number of associations a study is sent over in parallel
+    pub associations: usize,
+    /// C-STOREs kept outstanding on each association. Above 1 an
+    /// Asynchronous Operations Window is proposed, the PACS may grant less.
+    pub max_operations: u16,
+    pub tls: TlsConfig,
+}
+
+/// Object storage the encrypted studies are exchanged through.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    /// Root directory of the `local` backend.
+    pub path: PathBuf,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// S3 bucket configured in the `[s3]` section
+    S3,
+    /// Local or network mounted directory
+    Local,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3Config {
+    pub endpoint: String,
+    pub bucket: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MatrixConfig {
+    pub homeserver: String,
+    pub user: String,
+    /// Rooms notified about new shares, all joined rooms if empty.
+    pub rooms: Vec<String>,
+    /// Matrix users of the gateways whose shares are imported, e.g.
+    /// `@dcmshare:hospital.example.org`. Shares of anyone else are ignored.
+    pub gateways: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DbConfig {
+    pub dir: PathBuf,
+}
+
+/// Files the gateway keeps after it is done with them. There is no age or
+/// size limit, kept files have to be cleaned up by the operator.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    /// Keep received DICOM files in the run directory after they were uploaded.
+    pub keep_received_files: bool,
+    /// Keep decrypted downloads in the run directory after they were stored
+    /// in the PACS.
+    pub keep_downloaded_files: bool,
+}
+
+/// Retries of queued upload, notification and import jobs.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobsConfig {
+    /// Failed attempts after which a job is dead-lettered.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled with every further failure.
+    pub initial_backoff_secs: u64,
+    pub max_backoff_secs: u64,
+}
+
+impl Default for DicomConfig {
+    fn default() -> Self {
+        DicomConfig {
+            ae_title: "DCMSHARE".to_string(),
+            port: 11111,
+            run_dir: PathBuf::from(".dcmshare"),
+            quiet_period_secs: 30,
+            max_associations: 8,
+            upload_workers: 2,
+            upload_queue_size: 1000,
+            allowed_peers: vec![],
+            tls: TlsConfig::default(),
+        }
+    }
+}
+
+impl Default for PacsConfig {
+    fn default() -> Self {
+        PacsConfig {
+            default: "default".to_string(),
+            destinations: BTreeMap::from([("default".to_string(), PacsDestination::default())]),
+            routes: BTreeMap::new(),
+            import_without_manifest: false,
+        }
+    }
+}
+
+impl Default for PacsDestination {
+    fn default() -> Self {
+        PacsDestination {
+            ae_title: "ORTHANC".to_string(),
+            host: "127.0.0.1".to_string(),
+            port: 4242,
+            calling_ae_title: "DCMSHARE".to_string(),
+            max_pdu_length: 16384,
+            associations: 1,
+            max_operations: 1,
+            tls: TlsConfig::default(),
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            backend: StorageBackend::S3,
+            path: PathBuf::from(""),
+        }
+    }
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        JobsConfig {
+            max_attempts: 10,
+            initial_backoff_secs: 30,
+            max_backoff_secs: 3600,
+        }
+    }
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        S3Config {
+            endpoint: "https://nonelabs.com:9090".to_string(),
+            bucket: "studies".to_string(),
+        }
+    }
+}
+
+impl Default for MatrixConfig {
+    fn default() -> Self {
+        MatrixConfig {
+            homeserver: "https://nonelabs.com:4327".to_string(),
+            user: "@joker:serverella".to_string(),
+            rooms: vec![],
+            gateways: vec![],
+        }
+    }
+}
+
+impl Default for DbConfig {
+    fn default() -> Self {
+        DbConfig {
+            dir: PathBuf::from(""),
+        }
+    }
+}
+
+impl PacsConfig {
+    pub fn destination(&self, name: &str) -> Option<&PacsDestination> {
+        self.destinations.get(name)
+    }
+
+    /// Destination shares received in `room_id` are imported into.
+    pub fn destination_for_room(&self, room_id: &str) -> Option<&PacsDestination> {
+        let name = self.routes.get(room_id).unwrap_or(&self.default);
+        self.destination(name)
+    }
+}
+
+impl PacsDestination {
+    /// Address in the `AE@host:port` form expected by `dicom_ul`.
+    pub fn address(&self) -> String {
+        format!("{}@{}:{}", self.ae_title, self.host, self.port)
+    }
+}
+
+impl S3Config {
+    pub fn bucket_config(&self, secrets: &dyn SecretProvider) -> Result<BucketConfig, Whatever> {
+        Ok(BucketConfig {
+            endpoint: self.endpoint.clone(),
+            name: self.bucket.clone(),
+            access_key: secrets.require(secrets::S3_ACCESS_KEY)?,
+            secret_key: secrets.require(secrets::S3_SECRET_KEY)?,
+        })
+    }
+}
+
+impl StorageConfig {
+    pub fn open(&self, s3: &S3Config, secrets: &dyn SecretProvider) -> Result<Arc<dyn ObjectStore>, Whatever> {
+        Ok(match self.backend {
+            StorageBackend::S3 => Arc::new(
+                S3Store::new(&s3.bucket_config(secrets)?).whatever_context("could not open S3 bucket")?,
+            ),
+            StorageBackend::Local => Arc::new(
+                LocalStore::new(&self.path)
+                    .with_whatever_context(|_| format!("could not open storage directory {}", self.path.display()))?,
+            ),
+        })
+    }
+}
+
+impl GatewayConfig {
+    /// Reads the configuration file at `path`, applies the given profile and
+    /// then any `DCMSHARE_*` environment variables.
+    ///
+    /// A missing file is not an error, the built-in defaults are used instead.
+    pub fn load(path: &Path, profile: Option<&str>) -> Result<GatewayConfig, Whatever> {
+        Self::load_with_env(path, profile, std::env::vars())
+    }
+
+    fn load_with_env(
+        path: &Path,
+        profile: Option<&str>,
+        vars: impl Iterator<Item = (String, String)>,
+    ) -> Result<GatewayConfig, Whatever> {
+        let mut table = if path.exists() {
+            let content = fs::read_to_string(path)
+                .with_whatever_context(|_| format!("could not read {}", path.display()))?;
+            toml::from_str::<Table>(&content)
+                .with_whatever_context(|_| format!("could not parse {}", path.display()))?
+        } else {
+            Table::new()
+        };
+        let mut profiles = match table.remove("profiles") {
+            Some(Value::Table(profiles)) => profiles,
+            Some(_) => whatever!("`profiles` must be a table of named profiles"),
+            None => Table::new(),
+        };
+        if let Some(name) = profile {
+            match profiles.remove(name) {
+                Some(Value::Table(overrides)) => merge(&mut table, overrides),
+                Some(_) => whatever!("profile {:?} must be a table", name),
+                None => whatever!("unknown profile {:?} in {}", name, path.display()),
+            }
+        }
+        let mut config: GatewayConfig = Value::Table(table.clone())
+            .try_into()
+            .whatever_context("invalid configuration")?;
+        for (name, path, value) in env_overrides(vars) {
+            let value = match entry_kind(&path) {
+                EntryKind::Unknown => {
+                    warn!("Ignoring environment variable {}, it does not name a configuration entry", name);
+                    continue;
+                }
+                EntryKind::Text => Value::String(value),
+                EntryKind::Value => toml::from_str::<Table>(&format!("v = {}", value))
+                    .ok()
+                    .and_then(|mut t| t.remove("v"))
+                    .unwrap_or(Value::String(value)),
+            };
+            let overrides = path.iter().rev().fold(value, |value, key| {
+                Value::Table(Table::from_iter([(key.clone(), value)]))
+            });
+            let Value::Table(overrides) = overrides else { continue };
+            merge(&mut table, overrides);
+            config = Value::Table(table.clone())
+                .try_into()
+                .with_whatever_context(|_| format!("invalid value of environment variable {}", name))?;
+        }
+        Ok(config)
+    }
+
+    /// Checks the configuration so that mistakes are reported at startup
+    /// and not when the first association or message arrives.
+    pub fn validate(&self) -> Result<(), Whatever> {
+        validate_ae_title(&self.dicom.ae_title)?;
+        ensure_whatever!(self.dicom.port != 0, "DICOM port must not be 0");
+        ensure_whatever!(self.dicom.max_associations > 0, "max_associations must be at least 1");
+        ensure_whatever!(self.dicom.upload_workers > 0, "upload_workers must be at least 1");
+        ensure_whatever!(self.dicom.upload_queue_size > 0, "upload_queue_size must be at least 1");
+        AccessPolicy::new(&self.dicom.allowed_peers)?;
+        self.dicom.tls.acceptor()?;
+        ensure_whatever!(self.pacs.destination(&self.pacs.default).is_some(),
+            "default PACS destination {:?} is not configured", self.pacs.default);
+        for (room, name) in &self.pacs.routes {
+            ensure_whatever!(self.pacs.destination(name).is_some(),
+                "PACS destination {:?} routed from room {} is not configured", name, room);
+        }
+        for (name, destination) in &self.pacs.destinations {
+            destination.validate()
+                .with_whatever_context(|_| format!("invalid PACS destination {:?}", name))?;
+        }
+        Url::parse(&self.matrix.homeserver)
+            .with_whatever_context(|_| format!("invalid Matrix homeserver URL {:?}", self.matrix.homeserver))?;
+        ensure_whatever!(!self.matrix.user.is_empty(), "Matrix user must not be empty");
+        match self.storage.backend {
+            StorageBackend::S3 => {
+                Url::parse(&self.s3.endpoint)
+                    .with_whatever_context(|_| format!("invalid S3 endpoint URL {:?}", self.s3.endpoint))?;
+                ensure_whatever!(!self.s3.bucket.is_empty(), "S3 bucket must not be empty");
+            }
+            StorageBackend::Local => {
+                ensure_whatever!(!self.storage.path.as_os_str().is_empty(), "local storage path must not be empty");
+            }
+        }
+        ensure_whatever!(self.jobs.max_attempts > 0, "jobs max_attempts must be at least 1");
+        ensure_whatever!(self.jobs.initial_backoff_secs > 0, "jobs initial_backoff_secs must be at least 1");
+        ensure_whatever!(self.jobs.max_backoff_secs >= self.jobs.initial_backoff_secs,
+            "jobs max_backoff_secs must not be less than initial_backoff_secs");
+        Ok(())
+    }
+}
+
+impl PacsDestination {
+    fn validate(&self) -> Result<(), Whatever> {
+        validate_ae_title(&self.ae_title)?;
+        validate_ae_title(&self.calling_ae_title)?;
+        ensure_whatever!(!self.host.is_empty(), "host must not be empty");
+        ensure_whatever!(self.port != 0, "port must not be 0");
+        ensure_whatever!(self.max_pdu_length >= 4096, "max_pdu_length must be at least 4096");
+        ensure_whatever!(self.associations > 0, "associations must be at least 1");
+        ensure_whatever!(self.max_operations > 0, "max_operations must be at least 1");
+        self.tls.connector()?;
+        Ok(())
+    }
+}
+
+fn validate_ae_title(ae_title: &str) -> Result<(), Whatever> {
+    ensure_whatever!(!ae_title.trim().is_empty() && ae_title.len() <= 16,
+        "AE title must be between 1 and 16 characters, got {:?}", ae_title);
+    ensure_whatever!(ae_title.chars().all(|c| c.is_ascii_graphic() || c == ' ') && !ae_title.contains('\\'),
+        "AE title contains invalid characters: {:?}", ae_title);
+    Ok(())
+}
+
+/// Recursively merges `overrides` into `base`, tables are merged key by key
+/// while any other value replaces the one in `base`.
+fn merge(base: &mut Table, overrides: Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(value)) => merge(base, value),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Splits `DCMSHARE_<SECTION>_<KEY>` and `DCMSHARE_<TABLE>__<...>__<KEY>`
+/// variables into their name, lower-cased entry path and value.
+/// `DCMSHARE_SECRET_*` variables belong to the environment secret provider
+/// and are skipped.
+fn env_overrides(vars: impl Iterator<Item = (String, String)>) -> Vec<(String, Vec<String>, String)> {
+    let mut overrides = vec![];
+    for (name, value) in vars {
+        if name.starts_with(secrets::ENV_PREFIX) {
+            continue;
+        }
+        let Some(entry) = name.strip_prefix(ENV_PREFIX) else { continue };
+        let entry = entry.to_lowercase();
+        let path: Vec<String> = if entry.contains(ENV_PATH_SEPARATOR) {
+            entry.split(ENV_PATH_SEPARATOR).map(String::from).collect()
+        } else {
+            entry.splitn(2, '_').map(String::from).collect()
+        };
+        if path.len() < 2 || path.iter().any(|p| p.is_empty()) {
+            warn!("Ignoring environment variable {}, it does not name a configuration entry", name);
+            continue;
+        }
+        overrides.push((name, path, value));
+    }
+    overrides.sort_by(|(a, _, _), (b, _, _)| a.cmp(b));
+    overrides
+}
+
+/// How an environment variable's value is read for a configuration entry.
+#[derive(Debug, PartialEq, Eq)]
+enum EntryKind {
+    /// The path names no entry.
+    Unknown,
+    /// A string or path entry, the value is taken verbatim (so
+    /// `DCMSHARE_S3_BUCKET=2024` stays a string).
+    Text,
+    /// Any other entry, the value is parsed as TOML (so
+    /// `DCMSHARE_DICOM_PORT=104` is an integer) and falls back to a string.
+    Value,
+}
+
+/// Looks up the entry at `path` by walking the fields and maps the
+/// `Deserialize` implementation of [`GatewayConfig`] asks for.
+fn entry_kind(path: &[String]) -> EntryKind {
+    match <GatewayConfig as serde::Deserialize>::deserialize(EntryProbe { path }) {
+        Ok(_) => EntryKind::Unknown,
+        Err(ProbedEntry(kind)) => kind,
+    }
+}
+
+/// Ends the probe with the kind of the entry reached, any other error
+/// raised while deserializing means the path names no entry.
+#[derive(Debug)]
+struct ProbedEntry(EntryKind);
+
+impl std::fmt::Display for ProbedEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} entry", self.0)
+    }
+}
+
+impl std::error::Error for ProbedEntry {}
+
+impl serde::de::Error for ProbedEntry {
+    fn custom<T: std::fmt::Display>(_msg: T) -> Self {
+        ProbedEntry(EntryKind::Unknown)
+    }
+}
+
+/// Deserializer following `path` into structs and maps.
+struct EntryProbe<'a> {
+    path: &'a [String],
+}
+
+impl EntryProbe<'_> {
+    fn leaf(self, kind: EntryKind) -> ProbedEntry {
+        ProbedEntry(if self.path.is_empty() { kind } else { EntryKind::Unknown })
+    }
+}
+
+impl<'de> serde::Deserializer<'de> for EntryProbe<'_> {
+    type Error = ProbedEntry;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, ProbedEntry> {
+        Err(self.leaf(EntryKind::Value))
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, ProbedEntry> {
+        Err(self.leaf(EntryKind::Text))
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, ProbedEntry> {
+        Err(self.leaf(EntryKind::Text))
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ProbedEntry> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, ProbedEntry> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ProbedEntry> {
+        match self.path.split_first() {
+            Some((key, path)) => visitor.visit_map(EntryProbeMap { key: Some(key), path }),
+            None => Err(ProbedEntry(EntryKind::Value)),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, ProbedEntry> {
+        match self.path.split_first() {
+            Some((key, path)) if fields.contains(&key.as_str()) => {
+                visitor.visit_map(EntryProbeMap { key: Some(key), path })
+            }
+            Some(_) => Err(ProbedEntry(EntryKind::Unknown)),
+            None => Err(ProbedEntry(EntryKind::Value)),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char bytes byte_buf
+        unit unit_struct seq tuple tuple_struct enum identifier ignored_any
+    }
+}
+
+/// Map holding only the next key of the probed path.
+struct EntryProbeMap<'a> {
+    key: Option<&'a String>,
+    path: &'a [String],
+}
+
+impl<'de> MapAccess<'de> for EntryProbeMap<'_> {
+    type Error = ProbedEntry;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, ProbedEntry> {
+        match self.key.take() {
+            Some(key) => seed.deserialize(key.as_str().into_deserializer()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, ProbedEntry> {
+        seed.deserialize(EntryProbe { path: self.path })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        [dicom]
+        ae_title = "GATEWAY"
+        port = 104
+
+        [pacs.destinations.default]
+        host = "pacs.example.org"
+        port = 11112
+
+        [profiles.staging.dicom]
+        port = 11104
+
+        [profiles.staging.pacs.destinations.default]
+        host = "pacs-test.local"
+    "#;
+
+    fn load(profile: Option<&str>, vars: &[(&str, &str)]) -> Result<GatewayConfig, Whatever> {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), CONFIG).unwrap();
+        let vars = vars.iter().map(|(name, value)| (name.to_string(), value.to_string()));
+        GatewayConfig::load_with_env(file.path(), profile, vars.collect::<Vec<_>>().into_iter())
+    }
+
+    #[test]
+    fn profiles_override_nested_entries() {
+        let config = load(None, &[]).unwrap();
+        assert_eq!(config.dicom.port, 104);
+        assert_eq!(config.pacs.destinations["default"].host, "pacs.example.org");
+
+        let config = load(Some("staging"), &[]).unwrap();
+        assert_eq!(config.dicom.ae_title, "GATEWAY");
+        assert_eq!(config.dicom.port, 11104);
+        assert_eq!(config.pacs.destinations["default"].host, "pacs-test.local");
+        assert_eq!(config.pacs.destinations["default"].port, 11112);
+
+        assert!(load(Some("production"), &[]).is_err());
+    }
+
+    #[test]
+    fn environment_overrides_sections_and_nested_tables() {
+        let config = load(Some("staging"), &[
+            ("DCMSHARE_DICOM_PORT", "4104"),
+            ("DCMSHARE_S3_BUCKET", "studies-staging"),
+            ("DCMSHARE_DICOM__TLS__ENABLED", "true"),
+            ("DCMSHARE_PACS__DESTINATIONS__DEFAULT__HOST", "pacs.local"),
+            ("DCMSHARE_SECRET_DB_PASSWORD", "secret"),
+        ])
+        .unwrap();
+        assert_eq!(config.dicom.port, 4104);
+        assert_eq!(config.s3.bucket, "studies-staging");
+        assert!(config.dicom.tls.enabled);
+        assert_eq!(config.pacs.destinations["default"].host, "pacs.local");
+        assert_eq!(config.pacs.destinations["default"].port, 11112);
+    }
+
+    #[test]
+    fn unknown_environment_variables_are_ignored() {
+        let config = load(None, &[
+            ("DCMSHARE_HOME", "/opt/dcmshare"),
+            ("DCMSHARE_DICOM_COLOR", "blue"),
+            ("DCMSHARE_DICOM__TLS__CIPHER", "none"),
+            ("DCMSHARE_S3_BUCKET", "other"),
+        ])
+        .unwrap();
+        assert_eq!(config.s3.bucket, "other");
+    }
+
+    #[test]
+    fn environment_values_are_read_as_the_entry_type() {
+        let config = load(None, &[
+            ("DCMSHARE_S3_BUCKET", "2024"),
+            ("DCMSHARE_DB_DIR", "1.5"),
+            ("DCMSHARE_DICOM_AE_TITLE", "true"),
+            ("DCMSHARE_DICOM_PORT", "4104"),
+            ("DCMSHARE_STORAGE_BACKEND", "local"),
+            ("DCMSHARE_PACS__DESTINATIONS__RADIOLOGY__PORT", "104"),
+        ])
+        .unwrap();
+        assert_eq!(config.s3.bucket, "2024");
+        assert_eq!(config.db.dir, PathBuf::from("1.5"));
+        assert_eq!(config.dicom.ae_title, "true");
+        assert_eq!(config.dicom.port, 4104);
+        assert_eq!(config.storage.backend, StorageBackend::Local);
+        assert_eq!(config.pacs.destinations["radiology"].port, 104);
+    }
+
+    #[test]
+    fn entries_are_looked_up_in_the_configuration_structure() {
+        let path = |entry: &str| entry.split('.').map(String::from).collect::<Vec<_>>();
+        assert_eq!(entry_kind(&path("s3.bucket")), EntryKind::Text);
+        assert_eq!(entry_kind(&path("dicom.tls.enabled")), EntryKind::Value);
+        assert_eq!(entry_kind(&path("pacs.destinations.any.host")), EntryKind::Text);
+        assert_eq!(entry_kind(&path("dicom.color")), EntryKind::Unknown);
+        assert_eq!(entry_kind(&path("dicom.port.number")), EntryKind::Unknown);
+        assert_eq!(entry_kind(&path("home.dir")), EntryKind::Unknown);
+    }
+
+    #[test]
+    fn invalid_environment_values_are_errors() {
+        assert!(load(None, &[("DCMSHARE_DICOM_PORT", "eleven")]).is_err());
+        assert!(load(None, &[("DCMSHARE_STORAGE_BACKEND", "ftp")]).is_err());
+    }
+}
@@ -0,0 +1,201 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use dicom_ul::association::server::AccessControl;
+use dicom_ul::pdu::AssociationRJServiceUserReason;
+use serde_derive::Deserialize;
+use snafu::{ensure_whatever, whatever, ResultExt, Whatever};
+use tracing::warn;
+
+/// Entry of the `allowed_peers` list in the `[dicom]` configuration section.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AllowedPeer {
+    /// Calling AE title of the modality or PACS.
+    pub ae_title: String,
+    /// Addresses (`10.1.2.3`) or ranges (`10.1.2.0/24`) the AE title may
+    /// connect from, any address if empty.
+    #[serde(default)]
+    pub networks: Vec<String>,
+}
+
+/// IPv4 or IPv6 address range in CIDR notation.
+#[derive(Debug, Clone, Copy)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = Whatever;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .with_whatever_context(|_| format!("invalid address in network {:?}", s))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .with_whatever_context(|_| format!("invalid prefix length in network {:?}", s))?,
+            None => max_prefix,
+        };
+        ensure_whatever!(prefix <= max_prefix, "prefix length of network {:?} is too large", s);
+        Ok(IpNetwork { addr, prefix })
+    }
+}
+
+/// Calling AE titles, optionally bound to address ranges, that may open
+/// associations with the Store SCP. An empty policy accepts everyone.
+#[derive(Debug, Clone, Default)]
+pub struct AccessPolicy {
+    rules: Vec<(String, Vec<IpNetwork>)>,
+}
+
+impl AccessPolicy {
+    pub fn new(peers: &[AllowedPeer]) -> Result<AccessPolicy, Whatever> {
+        let mut rules = vec![];
+        for peer in peers {
+            let ae_title = peer.ae_title.trim();
+            if ae_title.is_empty() {
+                whatever!("allowed peer without AE title");
+            }
+            let networks = peer
+                .networks
+                .iter()
+                .map(|n| n.parse())
+                .collect::<Result<Vec<IpNetwork>, _>>()?;
+            rules.push((ae_title.to_string(), networks));
+        }
+        Ok(AccessPolicy { rules })
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Access control for an association requested from `peer`.
+    pub fn for_peer(&self, peer: Option<IpAddr>) -> PeerAccessControl<'_> {
+        PeerAccessControl { policy: self, peer }
+    }
+}
+
+/// [`AccessControl`] applying an [`AccessPolicy`] to one connecting peer.
+/// Refused requests are answered with an A-ASSOCIATE-RJ by `dicom_ul`.
+pub struct PeerAccessControl<'a> {
+    policy: &'a AccessPolicy,
+    peer: Option<IpAddr>,
+}
+
+impl AccessControl for PeerAccessControl<'_> {
+    fn check_access(
+        &self,
+        _this_ae_title: &str,
+        calling_ae_title: &str,
+        _called_ae_title: &str,
+    ) -> Result<(), AssociationRJServiceUserReason> {
+        if self.policy.is_open() {
+            return Ok(());
+        }
+        let calling_ae_title = calling_ae_title.trim();
+        let peer = self.peer.map(|p| p.to_string()).unwrap_or_else(|| "unknown address".to_string());
+        let mut rules = self
+            .policy
+            .rules
+            .iter()
+            .filter(|(ae_title, _)| ae_title == calling_ae_title)
+            .peekable();
+        if rules.peek().is_none() {
+            warn!("Refused association from {} at {}: AE title not allowed", calling_ae_title, peer);
+            return Err(AssociationRJServiceUserReason::CallingAETitleNotRecognized);
+        }
+        let allowed = rules.any(|(_, networks)| {
+            networks.is_empty() || self.peer.map(|p| networks.iter().any(|n| n.contains(p))).unwrap_or(false)
+        });
+        if !allowed {
+            warn!("Refused association from {} at {}: address not allowed for this AE title", calling_ae_title, peer);
+            return Err(AssociationRJServiceUserReason::NoReasonGiven);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contains(network: &str, ip: &str) -> bool {
+        network.parse::<IpNetwork>().unwrap().contains(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn matches_addresses_in_ipv4_ranges() {
+        assert!(contains("10.20.0.0/24", "10.20.0.1"));
+        assert!(contains("10.20.0.0/24", "10.20.0.255"));
+        assert!(!contains("10.20.0.0/24", "10.20.1.1"));
+        assert!(contains("10.20.0.7/24", "10.20.0.1"));
+        assert!(contains("10.20.0.7", "10.20.0.7"));
+        assert!(!contains("10.20.0.7", "10.20.0.8"));
+        assert!(contains("0.0.0.0/0", "192.168.1.1"));
+        assert!(contains("10.20.0.0/24", "::ffff:10.20.0.5"));
+    }
+
+    #[test]
+    fn matches_addresses_in_ipv6_ranges() {
+        assert!(contains("fd00:1::/64", "fd00:1::42"));
+        assert!(!contains("fd00:1::/64", "fd00:2::42"));
+        assert!(contains("::1", "::1"));
+        assert!(contains("::/0", "2001:db8::1"));
+        assert!(!contains("fd00::/8", "10.0.0.1"));
+        assert!(!contains("10.0.0.0/8", "fd00::1"));
+    }
+
+    #[test]
+    fn rejects_invalid_networks() {
+        for network in ["10.20.0.0/33", "fd00::/129", "10.20.0/24", "10.20.0.0/", "host/24"] {
+            assert!(network.parse::<IpNetwork>().is_err(), "{}", network);
+        }
+    }
+
+    #[test]
+    fn applies_rules_per_ae_title() {
+        let policy = AccessPolicy::new(&[
+            AllowedPeer { ae_title: "CT01".to_string(), networks: vec!["10.20.0.0/24".to_string()] },
+            AllowedPeer { ae_title: "PACS".to_string(), networks: vec![] },
+        ])
+        .unwrap();
+        let check = |ae_title: &str, peer: &str| {
+            policy.for_peer(Some(peer.parse().unwrap())).check_access("DCMSHARE", ae_title, "DCMSHARE")
+        };
+        assert!(check("CT01", "10.20.0.5").is_ok());
+        assert!(check("CT01 ", "10.20.0.5").is_ok());
+        assert!(check("CT01", "10.30.0.5").is_err());
+        assert!(check("PACS", "192.168.1.1").is_ok());
+        assert!(check("MR01", "10.20.0.5").is_err());
+        assert!(AccessPolicy::default().for_peer(None).check_access("DCMSHARE", "MR01", "DCMSHARE").is_ok());
+    }
+}
@@ -0,0 +1,401 @@
+//! Requestor side of a DICOM association.
+//!
+//! `dicom_ul`'s `ClientAssociation` opens its own `TcpStream` and proposes a
+//! fixed set of user information items. This one runs over any [`Transport`]
+//! and can negotiate an Asynchronous Operations Window (PS3.7 D.3.3.3), so
+//! several operations may be outstanding at a time.
+
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+
+use dicom_ul::pdu::reader::{DEFAULT_MAX_PDU, MAXIMUM_PDU_SIZE};
+use dicom_ul::pdu::{
+    AbortRQSource, AssociationRJResult, AssociationRJSource, PDataValue, PDataValueType,
+    PresentationContextProposed, PresentationContextResult, PresentationContextResultReason, UserVariableItem,
+};
+use dicom_ul::{read_pdu, write_pdu, Pdu, IMPLEMENTATION_CLASS_UID, IMPLEMENTATION_VERSION_NAME};
+use openssl::ssl::SslStream;
+use snafu::prelude::*;
+
+/// DICOM application context name (PS3.7 A.2.1)
+const APPLICATION_CONTEXT_NAME: &str = "1.2.840.10008.3.1.1.1";
+const PROTOCOL_VERSION: u16 = 1;
+/// Item type of the Asynchronous Operations Window sub-item
+const ASYNC_OPERATIONS_WINDOW: u8 = 0x53;
+/// Item length, presentation context ID and message control header of a PDV
+const PDV_HEADER_SIZE: usize = 6;
+
+/// Connection an association runs over.
+pub trait Transport: Read + Write + Send {
+    /// Closes the connection, errors are ignored.
+    fn close(&mut self);
+}
+
+impl Transport for TcpStream {
+    fn close(&mut self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+impl Transport for SslStream<TcpStream> {
+    fn close(&mut self) {
+        let _ = self.shutdown();
+        let _ = self.get_ref().shutdown(Shutdown::Both);
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not encode PDU"))]
+    Encode { source: dicom_ul::pdu::writer::Error },
+
+    #[snafu(display("Could not send PDU"))]
+    Wire { source: std::io::Error },
+
+    #[snafu(display("Could not receive PDU"))]
+    Receive { source: dicom_ul::pdu::reader::Error },
+
+    #[snafu(display("Association rejected ({:?}): {}", result, reason))]
+    Rejected { result: AssociationRJResult, reason: AssociationRJSource },
+
+    /// None of the proposed presentation contexts was accepted
+    NoAcceptedPresentationContexts,
+
+    #[snafu(display("Unexpected PDU: {}", pdu))]
+    UnexpectedPdu { pdu: String },
+}
+
+/// Parameters of an association request.
+pub struct AssociationOptions {
+    pub calling_ae_title: String,
+    pub called_ae_title: String,
+    /// Maximum length of the PDUs we receive
+    pub max_pdu_length: u32,
+    /// Outstanding operations to propose, 1 proposes no Asynchronous
+    /// Operations Window
+    pub max_operations: u16,
+    /// Abstract syntax and transfer syntaxes, the context ID of an entry is
+    /// its index * 2 + 1
+    pub presentation_contexts: Vec<(String, Vec<String>)>,
+}
+
+/// An established association.
+pub struct Association {
+    stream: Box<dyn Transport>,
+    presentation_contexts: Vec<PresentationContextResult>,
+    requestor_max_pdu_length: u32,
+    acceptor_max_pdu_length: u32,
+    max_operations: usize,
+    buffer: Vec<u8>,
+}
+
+impl AssociationOptions {
+    /// Requests the association over `stream`.
+    pub fn establish(self, stream: Box<dyn Transport>) -> Result<Association, Error> {
+        let mut user_variables = vec![
+            UserVariableItem::MaxLength(self.max_pdu_length),
+            UserVariableItem::ImplementationClassUID(IMPLEMENTATION_CLASS_UID.to_string()),
+            UserVariableItem::ImplementationVersionName(IMPLEMENTATION_VERSION_NAME.to_string()),
+        ];
+        if self.max_operations != 1 {
+            // invoked by us, performed by us: we never act as SCP
+            let mut window = self.max_operations.to_be_bytes().to_vec();
+            window.extend_from_slice(&1u16.to_be_bytes());
+            user_variables.push(UserVariableItem::Unknown(ASYNC_OPERATIONS_WINDOW, window));
+        }
+        let request = Pdu::AssociationRQ {
+            protocol_version: PROTOCOL_VERSION,
+            calling_ae_title: self.calling_ae_title,
+            called_ae_title: self.called_ae_title,
+            application_context_name: APPLICATION_CONTEXT_NAME.to_string(),
+            presentation_contexts: self
+                .presentation_contexts
+                .into_iter()
+                .enumerate()
+                .map(|(i, (abstract_syntax, transfer_syntaxes))| PresentationContextProposed {
+                    id: (i * 2 + 1) as u8,
+                    abstract_syntax,
+                    transfer_syntaxes,
+                })
+                .collect(),
+            user_variables,
+        };
+
+        let mut association = Association {
+            stream,
+            presentation_contexts: vec![],
+            requestor_max_pdu_length: self.max_pdu_length,
+            acceptor_max_pdu_length: DEFAULT_MAX_PDU,
+            max_operations: 1,
+            buffer: Vec::with_capacity(self.max_pdu_length as usize),
+        };
+        association.send(&request)?;
+        let response = read_pdu(&mut association.stream, MAXIMUM_PDU_SIZE, true).context(ReceiveSnafu)?;
+        match response {
+            Pdu::AssociationAC { presentation_contexts, user_variables, .. } => {
+                for item in user_variables {
+                    match item {
+                        // 0 is the maximum size admitted by the standard
+                        UserVariableItem::MaxLength(0) => association.acceptor_max_pdu_length = MAXIMUM_PDU_SIZE,
+                        UserVariableItem::MaxLength(length) => association.acceptor_max_pdu_length = length,
+                        UserVariableItem::Unknown(ASYNC_OPERATIONS_WINDOW, window) if window.len() == 4 => {
+                            // 0 leaves the window to us
+                            let granted = u16::from_be_bytes([window[0], window[1]]);
+                            association.max_operations = match granted {
+                                0 => self.max_operations,
+                                granted => granted.min(self.max_operations),
+                            }
+                            .max(1) as usize;
+                        }
+                        _ => {}
+                    }
+                }
+                association.presentation_contexts = presentation_contexts
+                    .into_iter()
+                    .filter(|pc| pc.reason == PresentationContextResultReason::Acceptance)
+                    .collect();
+                if association.presentation_contexts.is_empty() {
+                    let _ = association.abort();
+                    return NoAcceptedPresentationContextsSnafu.fail();
+                }
+                Ok(association)
+            }
+            Pdu::AssociationRJ { result, source } => RejectedSnafu { result, reason: source }.fail(),
+            pdu => {
+                let _ = association.abort();
+                UnexpectedPduSnafu { pdu: pdu.short_description() }.fail()
+            }
+        }
+    }
+}
+
+impl Association {
+    /// Accepted presentation contexts.
+    pub fn presentation_contexts(&self) -> &[PresentationContextResult] {
+        &self.presentation_contexts
+    }
+
+    /// Operations which may be outstanding at a time, 1 unless the acceptor
+    /// granted an Asynchronous Operations Window.
+    pub fn max_operations(&self) -> usize {
+        self.max_operations
+    }
+
+    pub fn send(&mut self, pdu: &Pdu) -> Result<(), Error> {
+        self.buffer.clear();
+        write_pdu(&mut self.buffer, pdu).context(EncodeSnafu)?;
+        self.stream.write_all(&self.buffer).context(WireSnafu)
+    }
+
+    pub fn receive(&mut self) -> Result<Pdu, Error> {
+        read_pdu(&mut self.stream, self.requestor_max_pdu_length, true).context(ReceiveSnafu)
+    }
+
+    /// Sends a command and its data set, in a single PDU if they fit into one
+    /// and split into as many P-DATA fragments as needed otherwise.
+    pub fn send_message(&mut self, presentation_context_id: u8, command: Vec<u8>, data: Option<Vec<u8>>) -> Result<(), Error> {
+        let max_fragment = self.acceptor_max_pdu_length as usize - PDV_HEADER_SIZE;
+        let data_len = data.as_ref().map(|data| data.len() + PDV_HEADER_SIZE).unwrap_or(0);
+        if command.len() + PDV_HEADER_SIZE + data_len <= self.acceptor_max_pdu_length as usize {
+            let mut values = vec![PDataValue {
+                presentation_context_id,
+                value_type: PDataValueType::Command,
+                is_last: true,
+                data: command,
+            }];
+            if let Some(data) = data {
+                values.push(PDataValue {
+                    presentation_context_id,
+                    value_type: PDataValueType::Data,
+                    is_last: true,
+                    data,
+                });
+            }
+            return self.send(&Pdu::PData { data: values });
+        }
+        self.send_fragments(presentation_context_id, PDataValueType::Command, &command, max_fragment)?;
+        if let Some(data) = data {
+            self.send_fragments(presentation_context_id, PDataValueType::Data, &data, max_fragment)?;
+        }
+        Ok(())
+    }
+
+    fn send_fragments(
+        &mut self,
+        presentation_context_id: u8,
+        value_type: PDataValueType,
+        data: &[u8],
+        max_fragment: usize,
+    ) -> Result<(), Error> {
+        let mut chunks = data.chunks(max_fragment).peekable();
+        if chunks.peek().is_none() {
+            return self.send(&Pdu::PData {
+                data: vec![PDataValue { presentation_context_id, value_type, is_last: true, data: vec![] }],
+            });
+        }
+        while let Some(chunk) = chunks.next() {
+            self.send(&Pdu::PData {
+                data: vec![PDataValue {
+                    presentation_context_id,
+                    value_type: value_type.clone(),
+                    is_last: chunks.peek().is_none(),
+                    data: chunk.to_vec(),
+                }],
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Exchanges the release messages and closes the connection.
+    pub fn release(mut self) -> Result<(), Error> {
+        let released = self.send(&Pdu::ReleaseRQ).and_then(|_| match self.receive()? {
+            Pdu::ReleaseRP => Ok(()),
+            pdu => UnexpectedPduSnafu { pdu: pdu.short_description() }.fail(),
+        });
+        self.stream.close();
+        released
+    }
+
+    /// Aborts the association and closes the connection.
+    pub fn abort(mut self) -> Result<(), Error> {
+        let aborted = self.send(&Pdu::AbortRQ { source: AbortRQSource::ServiceUser });
+        self.stream.close();
+        aborted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    const VERIFICATION: &str = "1.2.840.10008.1.1";
+    const IMPLICIT_VR_LE: &str = "1.2.840.10008.1.2";
+
+    /// Accepts one association, granting `window` if given, and returns the
+    /// request and the PDUs received until the release.
+    fn acceptor(window: Option<u16>) -> (u16, thread::JoinHandle<(Pdu, Vec<Pdu>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = read_pdu(&mut stream, MAXIMUM_PDU_SIZE, true).unwrap();
+            let Pdu::AssociationRQ { presentation_contexts, .. } = &request else { panic!("{:?}", request) };
+            let mut user_variables = vec![UserVariableItem::MaxLength(4096)];
+            if let Some(window) = window {
+                let mut data = window.to_be_bytes().to_vec();
+                data.extend_from_slice(&1u16.to_be_bytes());
+                user_variables.push(UserVariableItem::Unknown(ASYNC_OPERATIONS_WINDOW, data));
+            }
+            let accept = Pdu::AssociationAC {
+                protocol_version: PROTOCOL_VERSION,
+                calling_ae_title: "SCU".to_string(),
+                called_ae_title: "SCP".to_string(),
+                application_context_name: APPLICATION_CONTEXT_NAME.to_string(),
+                presentation_contexts: presentation_contexts
+                    .iter()
+                    .map(|pc| PresentationContextResult {
+                        id: pc.id,
+                        reason: PresentationContextResultReason::Acceptance,
+                        transfer_syntax: pc.transfer_syntaxes[0].clone(),
+                    })
+                    .collect(),
+                user_variables,
+            };
+            write_pdu(&mut stream, &accept).unwrap();
+            let mut received = vec![];
+            loop {
+                match read_pdu(&mut stream, MAXIMUM_PDU_SIZE, true).unwrap() {
+                    Pdu::ReleaseRQ => break,
+                    pdu => received.push(pdu),
+                }
+            }
+            write_pdu(&mut stream, &Pdu::ReleaseRP).unwrap();
+            (request, received)
+        });
+        (port, handle)
+    }
+
+    fn options(max_operations: u16) -> AssociationOptions {
+        AssociationOptions {
+            calling_ae_title: "SCU".to_string(),
+            called_ae_title: "SCP".to_string(),
+            max_pdu_length: 16384,
+            max_operations,
+            presentation_contexts: vec![
+                (VERIFICATION.to_string(), vec![IMPLICIT_VR_LE.to_string()]),
+                (VERIFICATION.to_string(), vec![IMPLICIT_VR_LE.to_string()]),
+            ],
+        }
+    }
+
+    fn window_item(pdu: &Pdu) -> Option<Vec<u8>> {
+        let Pdu::AssociationRQ { user_variables, .. } = pdu else { return None };
+        user_variables.iter().find_map(|item| match item {
+            UserVariableItem::Unknown(ASYNC_OPERATIONS_WINDOW, data) => Some(data.clone()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn negotiates_the_asynchronous_operations_window() {
+        let (port, scp) = acceptor(Some(4));
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let association = options(8).establish(Box::new(stream)).unwrap();
+        assert_eq!(association.max_operations(), 4);
+        let ids: Vec<u8> = association.presentation_contexts().iter().map(|pc| pc.id).collect();
+        assert_eq!(ids, vec![1, 3]);
+        association.release().unwrap();
+
+        let (request, _) = scp.join().unwrap();
+        assert_eq!(window_item(&request), Some(vec![0, 8, 0, 1]));
+    }
+
+    #[test]
+    fn falls_back_to_one_operation_without_a_window() {
+        let (port, scp) = acceptor(None);
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let association = options(8).establish(Box::new(stream)).unwrap();
+        assert_eq!(association.max_operations(), 1);
+        association.release().unwrap();
+        scp.join().unwrap();
+
+        let (port, scp) = acceptor(Some(4));
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let association = options(1).establish(Box::new(stream)).unwrap();
+        assert_eq!(association.max_operations(), 1);
+        association.release().unwrap();
+        let (request, _) = scp.join().unwrap();
+        assert_eq!(window_item(&request), None);
+    }
+
+    #[test]
+    fn fragments_data_sets_to_the_acceptor_pdu_length() {
+        let (port, scp) = acceptor(None);
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut association = options(1).establish(Box::new(stream)).unwrap();
+        let data: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        association.send_message(1, vec![1, 2, 3, 4], Some(data.clone())).unwrap();
+        association.send_message(1, vec![5, 6], Some(vec![7, 8])).unwrap();
+        association.release().unwrap();
+
+        let (_, received) = scp.join().unwrap();
+        let values: Vec<PDataValue> = received
+            .into_iter()
+            .flat_map(|pdu| match pdu {
+                Pdu::PData { data } => data,
+                pdu => panic!("{:?}", pdu),
+            })
+            .collect();
+        assert!(values.iter().all(|v| v.data.len() + PDV_HEADER_SIZE <= 4096));
+        let (first, second) = values.split_at(values.len() - 2);
+        assert_eq!(first[0].value_type, PDataValueType::Command);
+        let sent: Vec<u8> = first[1..].iter().flat_map(|v| v.data.clone()).collect();
+        assert_eq!(sent, data);
+        assert!(first[1..].iter().rev().skip(1).all(|v| !v.is_last && v.value_type == PDataValueType::Data));
+        assert!(first.last().unwrap().is_last);
+        assert_eq!(second[0].data, vec![5, 6]);
+        assert_eq!(second[1].data, vec![7, 8]);
+    }
+}
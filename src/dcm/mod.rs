@@ -1,2 +1,6 @@
+pub mod access;
+pub mod association;
 pub mod storescp;
 pub mod storescu;
+pub mod tls;
+pub mod transcode;
\ No newline at end of file
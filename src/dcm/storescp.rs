@@ -1,28 +1,24 @@
-use sha2::{Sha256, Digest};
-
-use sodiumoxide::crypto::secretbox;
-use sodiumoxide::randombytes;
-
 use serde::Serialize;
 use serde_derive::Deserialize;
 
-use std::{fs, net::{Ipv4Addr, SocketAddrV4, TcpListener, TcpStream}, path::PathBuf};
+use std::{net::{IpAddr, TcpStream}, path::{Path, PathBuf}};
 
-use clap::Parser;
 use dicom_core::{dicom_value, DataElement, PrimitiveValue, VR};
 use dicom_dictionary_std::tags;
-use dicom_dictionary_std::tags::STUDY_INSTANCE_UID;
 use dicom_encoding::transfer_syntax::TransferSyntaxIndex;
 use dicom_object::{FileMetaTableBuilder, InMemDicomObject, StandardDataDictionary};
 use dicom_transfer_syntax_registry::TransferSyntaxRegistry;
 use dicom_ul::{pdu::PDataValueType, Pdu};
-use dicom_object::FileDicomObject;
 use snafu::{OptionExt, ResultExt, Whatever};
-use tracing::{error, info, warn, Level};
-use microkv::MicroKV;
-use rand::Rng;
+use tracing::{error, info, warn};
+use dicom_ul::association::ServerAssociation;
+use crate::dcm::access::AccessPolicy;
+
+/// Verification SOP Class, used by C-ECHO
+pub const VERIFICATION: &str = "1.2.840.10008.1.1";
 
 pub static ABSTRACT_SYNTAXES: &[&str] = &[
+    VERIFICATION,
     "1.2.840.10008.5.1.4.1.1.2",
     "1.2.840.10008.5.1.4.1.1.2.1",
     "1.2.840.10008.5.1.4.1.1.9",
@@ -62,6 +58,15 @@ pub static ABSTRACT_SYNTAXES: &[&str] = &[
     "1.2.840.10008.5.1.4.1.1.88.33",
 ];
 
+// C-STORE response status codes, see DICOM PS3.4 Annex B.2.3
+pub const STATUS_SUCCESS: u16 = 0x0000;
+/// Warning: data elements were coerced to store the instance
+pub const STATUS_COERCION_OF_DATA_ELEMENTS: u16 = 0xB000;
+pub const STATUS_SOP_CLASS_NOT_SUPPORTED: u16 = 0x0122;
+pub const STATUS_OUT_OF_RESOURCES: u16 = 0xA700;
+pub const STATUS_DATA_SET_DOES_NOT_MATCH_SOP_CLASS: u16 = 0xA900;
+pub const STATUS_CANNOT_UNDERSTAND: u16 = 0xC000;
+
 #[derive(Serialize, Deserialize)]
 #[derive(Debug)]
 pub struct StoreScp {
@@ -69,17 +74,29 @@ pub struct StoreScp {
     pub run_dir: PathBuf,
 }
 
-pub fn receive(scu_stream: TcpStream, ae_title: String, run_dir: PathBuf) -> Result<String, Whatever> {
+/// Instance received in an association and written to the run directory.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredInstance {
+    /// Empty for instances queued by earlier versions
+    #[serde(default)]
+    pub study_instance_uid: String,
+    pub sop_class_uid: String,
+    pub sop_instance_uid: String,
+    pub series_instance_uid: String,
+    pub transfer_syntax_uid: String,
+    /// Calling AE title of the association the instance was received in
+    pub calling_ae_title: String,
+    pub file: PathBuf,
+}
+
+/// Receives the instances of one association. `peer` is the address of the
+/// SCU, which differs from the address of `scu_stream` when TLS is relayed.
+pub fn receive(scu_stream: TcpStream, peer: Option<IpAddr>, ae_title: String, run_dir: PathBuf, access: &AccessPolicy) -> Result<Vec<StoredInstance>, Whatever> {
 
-    let mut buffer: Vec<u8> = Vec::with_capacity(16384);
-    let mut instance_buffer: Vec<u8> = Vec::with_capacity(1024 * 1024);
-    let mut msgid = 1;
-    let mut sop_class_uid = "".to_string();
-    let mut sop_instance_uid = "".to_string();
     let mut options = dicom_ul::association::ServerAssociationOptions::new()
-        .accept_any()
+        .ae_access_control(access.for_peer(peer))
         .ae_title(ae_title);
-    let mut file_path = run_dir.clone();
+    let mut stored_instances = vec![];
 
     for ts in TransferSyntaxRegistry.iter() {
         if !ts.unsupported() {
@@ -101,6 +118,32 @@ pub fn receive(scu_stream: TcpStream, ae_title: String, run_dir: PathBuf) -> Res
             association.presentation_contexts()
         );
 
+    // instances stored before a failure were acknowledged to the SCU, they
+    // are kept and the association is aborted
+    match serve(&mut association, &run_dir, &mut stored_instances) {
+        Ok(()) => info!("Dropping connection with {}", association.client_ae_title()),
+        Err(e) => {
+            error!(
+                "Aborting association with {} after {} stored instances: {}",
+                association.client_ae_title(), stored_instances.len(), snafu::Report::from_error(e)
+            );
+            if let Err(e) = association.abort() {
+                warn!("Could not abort association: {}", snafu::Report::from_error(e));
+            }
+        }
+    }
+    Ok(stored_instances)
+}
+
+/// Answers the requests of an established association until it is released
+/// or closed, adding the instances it stores to `stored_instances`.
+fn serve(association: &mut ServerAssociation, run_dir: &Path, stored_instances: &mut Vec<StoredInstance>) -> Result<(), Whatever> {
+    let mut buffer: Vec<u8> = Vec::with_capacity(16384);
+    let mut instance_buffer: Vec<u8> = Vec::with_capacity(1024 * 1024);
+    let mut msgid = 1;
+    let mut sop_class_uid = "".to_string();
+    let mut sop_instance_uid = "".to_string();
+
     loop {
         match association.receive() {
             Ok(mut pdu) => {
@@ -121,6 +164,41 @@ pub fn receive(scu_stream: TcpStream, ae_title: String, run_dir: PathBuf) -> Res
                             let v = &data_value.data;
                             let obj = InMemDicomObject::read_dataset_with_ts(v.as_slice(), &ts)
                                 .whatever_context("failed to read incoming DICOM command")?;
+                            let command_field = obj
+                                .element(tags::COMMAND_FIELD)
+                                .whatever_context("Missing Command Field")?
+                                .to_int::<u16>()
+                                .whatever_context("Command Field is not an integer")?;
+                            if command_field == 0x0030 {
+                                // C-ECHO-RQ
+                                let echo_msgid = obj
+                                    .element(tags::MESSAGE_ID)
+                                    .whatever_context("Missing Message ID")?
+                                    .to_int()
+                                    .whatever_context("Message ID is not an integer")?;
+                                let echo_sop_class_uid = obj
+                                    .element(tags::AFFECTED_SOP_CLASS_UID)
+                                    .whatever_context("missing Affected SOP Class UID")?
+                                    .to_str()
+                                    .whatever_context("could not retrieve Affected SOP Class UID")?
+                                    .to_string();
+                                info!("Received C-ECHO from {}", association.client_ae_title());
+                                let rsp = create_cecho_response(echo_msgid, &echo_sop_class_uid);
+                                let mut rsp_data = Vec::new();
+                                rsp.write_dataset_with_ts(&mut rsp_data, &ts)
+                                    .whatever_context("could not write C-ECHO response object")?;
+                                association
+                                    .send(&Pdu::PData {
+                                        data: vec![dicom_ul::pdu::PDataValue {
+                                            presentation_context_id: data[0].presentation_context_id,
+                                            value_type: PDataValueType::Command,
+                                            is_last: true,
+                                            data: rsp_data,
+                                        }],
+                                    })
+                                    .whatever_context("failed to send C-ECHO response to SCU")?;
+                                continue;
+                            }
                             msgid = obj
                                 .element(tags::MESSAGE_ID)
                                 .whatever_context("Missing Message ID")?
@@ -147,44 +225,36 @@ pub fn receive(scu_stream: TcpStream, ae_title: String, run_dir: PathBuf) -> Res
                                 .iter()
                                 .find(|pc| pc.id == data[0].presentation_context_id)
                                 .whatever_context("missing presentation context")?;
-                            let ts = &presentation_context.transfer_syntax;
-
-                            let obj = InMemDicomObject::read_dataset_with_ts(
-                                instance_buffer.as_slice(),
-                                TransferSyntaxRegistry.get(ts).unwrap(),
-                            )
-                                .whatever_context("failed to read DICOM data object")?;
-                            let file_meta = FileMetaTableBuilder::new()
-                                .media_storage_sop_class_uid(
-                                    obj.element(tags::SOP_CLASS_UID)
-                                        .whatever_context("missing SOP Class UID")?
-                                        .to_str()
-                                        .whatever_context("could not retrieve SOP Class UID")?,
-                                )
-                                .media_storage_sop_instance_uid(
-                                    obj.element(tags::SOP_INSTANCE_UID)
-                                        .whatever_context("missing SOP Instance UID")?
-                                        .to_str()
-                                        .whatever_context("missing SOP Instance UID")?,
-                                )
-                                .transfer_syntax(ts)
-                                .build()
-                                .whatever_context("failed to build DICOM meta file information")?;
-                            let file_obj = obj.with_exact_meta(file_meta);
-                            file_path
-                                .push(sop_instance_uid.trim_end_matches('\0').to_string());
-                            file_obj
-                                .write_to_file(&file_path)
-                                .whatever_context("could not save DICOM object to file")?;
-                            info!("Stored {}", file_path.display());
+                            let ts = presentation_context.transfer_syntax.clone();
 
+                            let status = match store_instance(
+                                &instance_buffer,
+                                &ts,
+                                &sop_class_uid,
+                                &sop_instance_uid,
+                                association.client_ae_title(),
+                                run_dir,
+                            ) {
+                                Ok((status, instance)) => {
+                                    info!("Stored {}", instance.file.display());
+                                    stored_instances.push(instance);
+                                    status
+                                }
+                                Err(failure) => {
+                                    warn!(
+                                        "Could not store instance {} (status code {:04X}H): {}",
+                                        sop_instance_uid.trim_end_matches('\0'), failure.status, failure.reason
+                                    );
+                                    failure.status
+                                }
+                            };
 
                             let ts =
                                 dicom_transfer_syntax_registry::entries::IMPLICIT_VR_LITTLE_ENDIAN
                                     .erased();
 
                             let obj =
-                                create_cstore_response(msgid, &sop_class_uid, &sop_instance_uid);
+                                create_cstore_response(msgid, &sop_class_uid, &sop_instance_uid, status);
 
                             let mut obj_data = Vec::new();
 
@@ -230,14 +300,164 @@ pub fn receive(scu_stream: TcpStream, ae_title: String, run_dir: PathBuf) -> Res
             }
         }
     }
-    info!("Dropping connection with {}", association.client_ae_title());
-    Ok(sop_instance_uid)
+    Ok(())
+}
+
+/// Reason an instance could not be stored, reported to the SCU as `status`.
+struct StoreFailure {
+    status: u16,
+    reason: String,
+}
+
+impl StoreFailure {
+    fn new(status: u16, reason: impl Into<String>) -> StoreFailure {
+        StoreFailure { status, reason: reason.into() }
+    }
+}
+
+/// Whether `uid` is a UID of at most 64 digits and dots, which is safe to
+/// use as a file name.
+fn is_uid(uid: &str) -> bool {
+    (1..=64).contains(&uid.len()) && uid.chars().all(|c| c.is_ascii_digit() || c == '.')
+}
+
+/// Decodes a received data set and writes it to `run_dir`.
+///
+/// Returns the C-STORE status to report, which is a warning if the SOP
+/// Class or Instance UID had to be taken from the command.
+fn store_instance(
+    instance_buffer: &[u8],
+    ts: &str,
+    sop_class_uid: &str,
+    sop_instance_uid: &str,
+    calling_ae_title: &str,
+    run_dir: &Path,
+) -> Result<(u16, StoredInstance), StoreFailure> {
+    let sop_class_uid = sop_class_uid.trim_end_matches('\0');
+    let sop_instance_uid = sop_instance_uid.trim_end_matches('\0');
+    if !ABSTRACT_SYNTAXES.contains(&sop_class_uid) || sop_class_uid == VERIFICATION {
+        return Err(StoreFailure::new(STATUS_SOP_CLASS_NOT_SUPPORTED, format!("SOP class {} is not supported", sop_class_uid)));
+    }
+    // the SOP Instance UID names the file in the run directory
+    if !is_uid(sop_instance_uid) {
+        return Err(StoreFailure::new(STATUS_CANNOT_UNDERSTAND, format!("invalid SOP Instance UID {:?}", sop_instance_uid)));
+    }
+    let ts = TransferSyntaxRegistry
+        .get(ts)
+        .ok_or_else(|| StoreFailure::new(STATUS_CANNOT_UNDERSTAND, format!("unsupported transfer syntax {}", ts)))?;
+    let mut obj = InMemDicomObject::read_dataset_with_ts(instance_buffer, ts)
+        .map_err(|e| StoreFailure::new(STATUS_CANNOT_UNDERSTAND, format!("failed to read DICOM data object: {}", e)))?;
+
+    let mut status = STATUS_SUCCESS;
+    for (tag, name, expected) in [
+        (tags::SOP_CLASS_UID, "SOP Class UID", sop_class_uid),
+        (tags::SOP_INSTANCE_UID, "SOP Instance UID", sop_instance_uid),
+    ] {
+        let value = obj
+            .element(tag)
+            .ok()
+            .and_then(|e| e.to_str().ok())
+            .map(|v| v.trim_end_matches('\0').to_string());
+        match value {
+            Some(value) if value == expected => {}
+            Some(value) => {
+                return Err(StoreFailure::new(
+                    STATUS_DATA_SET_DOES_NOT_MATCH_SOP_CLASS,
+                    format!("{} {} does not match {} in the command", name, value, expected),
+                ));
+            }
+            None => {
+                obj.put(DataElement::new(tag, VR::UI, dicom_value!(Str, expected)));
+                status = STATUS_COERCION_OF_DATA_ELEMENTS;
+            }
+        }
+    }
+    let series_instance_uid = obj
+        .element(tags::SERIES_INSTANCE_UID)
+        .ok()
+        .and_then(|e| e.to_str().ok())
+        .map(|v| v.trim_end_matches('\0').to_string())
+        .ok_or_else(|| StoreFailure::new(STATUS_DATA_SET_DOES_NOT_MATCH_SOP_CLASS, "missing Series Instance UID"))?;
+    // instances without one are kept and fail to upload
+    let study_instance_uid = obj
+        .element(tags::STUDY_INSTANCE_UID)
+        .ok()
+        .and_then(|e| e.to_str().ok())
+        .map(|v| v.trim_end_matches('\0').to_string())
+        .unwrap_or_default();
+
+    let file_meta = FileMetaTableBuilder::new()
+        .media_storage_sop_class_uid(sop_class_uid)
+        .media_storage_sop_instance_uid(sop_instance_uid)
+        .transfer_syntax(ts.uid())
+        .build()
+        .map_err(|e| StoreFailure::new(STATUS_CANNOT_UNDERSTAND, format!("failed to build DICOM meta file information: {}", e)))?;
+    let file_obj = obj.with_exact_meta(file_meta);
+    let file_path = run_dir.join(sop_instance_uid);
+    file_obj
+        .write_to_file(&file_path)
+        .map_err(|e| StoreFailure::new(STATUS_OUT_OF_RESOURCES, format!("could not save DICOM object to file: {}", e)))?;
+    Ok((status, StoredInstance {
+        study_instance_uid,
+        sop_class_uid: sop_class_uid.to_string(),
+        sop_instance_uid: sop_instance_uid.to_string(),
+        series_instance_uid,
+        transfer_syntax_uid: ts.uid().to_string(),
+        calling_ae_title: calling_ae_title.trim().to_string(),
+        file: file_path,
+    }))
+}
+
+fn create_cecho_response(
+    message_id: u16,
+    sop_class_uid: &str,
+) -> InMemDicomObject<StandardDataDictionary> {
+    let mut obj = InMemDicomObject::new_empty();
+
+    // group length
+    obj.put(DataElement::new(
+        tags::COMMAND_GROUP_LENGTH,
+        VR::UL,
+        PrimitiveValue::from(8 + sop_class_uid.len() as i32 + 8 + 2 + 8 + 2 + 8 + 2 + 8 + 2),
+    ));
+    // service
+    obj.put(DataElement::new(
+        tags::AFFECTED_SOP_CLASS_UID,
+        VR::UI,
+        dicom_value!(Str, sop_class_uid),
+    ));
+    // command
+    obj.put(DataElement::new(
+        tags::COMMAND_FIELD,
+        VR::US,
+        dicom_value!(U16, [0x8030]),
+    ));
+    // message ID being responded to
+    obj.put(DataElement::new(
+        tags::MESSAGE_ID_BEING_RESPONDED_TO,
+        VR::US,
+        dicom_value!(U16, [message_id]),
+    ));
+    // data set type: no data set
+    obj.put(DataElement::new(
+        tags::COMMAND_DATA_SET_TYPE,
+        VR::US,
+        dicom_value!(U16, [0x0101]),
+    ));
+    obj.put(DataElement::new(
+        tags::STATUS,
+        VR::US,
+        dicom_value!(U16, [0x0000]),
+    ));
+
+    obj
 }
 
 fn create_cstore_response(
     message_id: u16,
     sop_class_uid: &str,
     sop_instance_uid: &str,
+    status: u16,
 ) -> InMemDicomObject<StandardDataDictionary> {
     let mut obj = InMemDicomObject::new_empty();
 
@@ -287,7 +507,7 @@ fn create_cstore_response(
     obj.put(DataElement::new(
         tags::STATUS,
         VR::US,
-        dicom_value!(U16, [0x0000]),
+        dicom_value!(U16, [status]),
     ));
     // SOPInstanceUID
     obj.put(DataElement::new(
@@ -298,3 +518,20 @@ fn create_cstore_response(
 
     obj
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_instance_uids_which_are_no_file_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let ct_image_storage = "1.2.840.10008.5.1.4.1.1.2";
+        for uid in ["../../etc/passwd", "/tmp/instance", "1.2.3/4", "", &"1".repeat(65)] {
+            let failure = store_instance(&[], "1.2.840.10008.1.2.1", ct_image_storage, uid, "CT01", dir.path()).unwrap_err();
+            assert_eq!(failure.status, STATUS_CANNOT_UNDERSTAND, "{:?}", uid);
+        }
+        assert!(is_uid("1.2.826.0.1.3680043.2.1125.1"));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
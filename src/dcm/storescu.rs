@@ -4,18 +4,23 @@ use dicom_dictionary_std::tags;
 use dicom_encoding::transfer_syntax;
 use dicom_object::{mem::InMemDicomObject, open_file, StandardDataDictionary};
 use dicom_transfer_syntax_registry::TransferSyntaxRegistry;
-use dicom_ul::{
-    association::ClientAssociationOptions,
-    pdu::{PDataValue, PDataValueType, Pdu},
-};
+use dicom_ul::pdu::{PDataValueType, Pdu};
 use snafu::prelude::*;
-use snafu::{Report, Whatever};
-use std::collections::HashSet;
+use snafu::Report;
+use crate::config::PacsDestination;
+use crate::dcm::association::{self, Association, AssociationOptions, Transport};
+use crate::dcm::storescp::VERIFICATION;
+use crate::dcm::tls;
+use crate::dcm::transcode::{can_transcode, proposed_transfer_syntaxes, transcode};
+use std::collections::HashMap;
 use std::ffi::OsStr;
-use std::io::Write;
+use std::net::TcpStream;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
 use std::time::Duration;
-use tracing::{debug, error, info, warn, Level};
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, warn};
 use transfer_syntax::TransferSyntaxIndex;
 use walkdir::WalkDir;
 use snafu::prelude::*;
@@ -40,35 +45,128 @@ struct DicomFile {
 pub enum Error {
     /// Could not initialize SCU
     InitScu {
-        source: dicom_ul::association::client::Error,
+        source: association::Error,
+    },
+
+    /// None of the given files could be opened as DICOM
+    NoFiles,
+
+    #[snafu(display("Unexpected SCP response: {}", pdu))]
+    UnexpectedResponse {
+        pdu: String,
     },
 
 
     #[snafu(whatever, display("{}", message))]
     Other {
         message: String,
-        #[snafu(source(from(Box<dyn std::error::Error + 'static>, Some)))]
-        source: Option<Box<dyn std::error::Error + 'static>>,
+        #[snafu(source(from(Box<dyn std::error::Error + Send + Sync + 'static>, Some)))]
+        source: Option<Box<dyn std::error::Error + Send + Sync + 'static>>,
     },
 }
 
+/// Outcome of a single instance of a [`send`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InstanceStatus {
+    Stored,
+    /// Stored, but the PACS returned a warning status code
+    Warning(u16),
+    /// Rejected by the PACS with the given status code
+    Failed(u16),
+    /// Not transferred, e.g. no presentation context was accepted for it
+    NotSent(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct InstanceResult {
+    pub file: PathBuf,
+    pub sop_instance_uid: String,
+    pub status: InstanceStatus,
+}
 
-pub fn send(files: Vec<PathBuf>) -> Result<(), Error> {
+/// Per-instance results of a [`send`].
+#[derive(Debug, Clone, Default)]
+pub struct SendReport {
+    pub instances: Vec<InstanceResult>,
+}
 
-    tracing::subscriber::set_global_default(
-        tracing_subscriber::FmtSubscriber::builder()
-            .with_max_level(if true { Level::DEBUG } else { Level::INFO })
-            .finish(),
-    )
-        .whatever_context("Could not set up global logging subscriber")
-        .unwrap_or_else(|e: Whatever| {
-            eprintln!("[ERROR] {}", Report::from_error(e));
+impl SendReport {
+    pub fn stored(&self) -> usize {
+        self.count(|status| matches!(status, InstanceStatus::Stored | InstanceStatus::Warning(_)))
+    }
+
+    pub fn warnings(&self) -> usize {
+        self.count(|status| matches!(status, InstanceStatus::Warning(_)))
+    }
+
+    pub fn failed(&self) -> usize {
+        self.count(|status| matches!(status, InstanceStatus::Failed(_) | InstanceStatus::NotSent(_)))
+    }
+
+    fn count(&self, filter: impl Fn(&InstanceStatus) -> bool) -> usize {
+        self.instances.iter().filter(|i| filter(&i.status)).count()
+    }
+}
+
+/// Progress of a [`send`], reported as the instances are processed.
+#[derive(Debug, Clone)]
+pub enum SendProgress {
+    /// The association is established, `total` instances will be sent
+    Started { total: usize },
+    /// The outcome of one more instance is known
+    Instance { done: usize, total: usize, result: InstanceResult },
+}
+
+/// Collects the [`SendReport`] of all associations and reports every result
+/// as progress.
+struct Tracker<'a> {
+    report: Mutex<SendReport>,
+    total: usize,
+    progress: &'a (dyn Fn(SendProgress) + Sync),
+}
+
+impl Tracker<'_> {
+    fn push(&self, file: &DicomFile, status: InstanceStatus) {
+        let result = InstanceResult {
+            file: file.file.clone(),
+            sop_instance_uid: file.sop_instance_uid.clone(),
+            status,
+        };
+        let done = {
+            let mut report = self.report.lock().unwrap();
+            report.instances.push(result.clone());
+            report.instances.len()
+        };
+        (self.progress)(SendProgress::Instance {
+            done,
+            total: self.total,
+            result,
         });
+    }
 
-    let addr: String = "ORTHANC@127.0.0.1:4242".to_string();
+    fn is_reported(&self, file: &DicomFile) -> bool {
+        self.report.lock().unwrap().instances.iter().any(|i| i.file == file.file)
+    }
+}
+
+/// Stores the DICOM files in `files`, directories are searched recursively,
+/// in `destination`. The files are distributed over up to
+/// `destination.associations` associations which are run in parallel.
+///
+/// Fails if no association could be established or all of them broke down,
+/// the outcome of the individual instances is returned in the [`SendReport`].
+/// This blocks on network IO, use [`send_async`] from async code.
+///
+/// Every association keeps up to `destination.max_operations` C-STOREs in
+/// flight if the PACS grants an Asynchronous Operations Window, one at a time
+/// otherwise.
+pub fn send(
+    files: Vec<PathBuf>,
+    destination: &PacsDestination,
+    progress: &(dyn Fn(SendProgress) + Sync),
+) -> Result<SendReport, Error> {
     let mut checked_files: Vec<PathBuf> = vec![];
     let mut dicom_files: Vec<DicomFile> = vec![];
-    let mut presentation_contexts = HashSet::new();
 
     for file in files {
         if file.is_dir() {
@@ -91,10 +189,6 @@ pub fn send(files: Vec<PathBuf>) -> Result<(), Error> {
 
         match check_file(&file) {
             Ok(dicom_file) => {
-                presentation_contexts.insert((
-                    dicom_file.sop_class_uid.to_string(),
-                    dicom_file.file_transfer_syntax.clone(),
-                ));
                 dicom_files.push(dicom_file);
             }
             Err(_) => {
@@ -103,33 +197,98 @@ pub fn send(files: Vec<PathBuf>) -> Result<(), Error> {
         }
     }
 
-    if dicom_files.is_empty() {
-        eprintln!("No supported files to transfer");
-        std::process::exit(-1);
+    ensure!(!dicom_files.is_empty(), NoFilesSnafu);
+
+    progress(SendProgress::Started { total: dicom_files.len() });
+    let tracker = Tracker {
+        report: Mutex::new(SendReport::default()),
+        total: dicom_files.len(),
+        progress,
+    };
+    let associations = destination.associations.clamp(1, dicom_files.len());
+    let mut batches: Vec<Vec<DicomFile>> = (0..associations).map(|_| vec![]).collect();
+    for (i, file) in dicom_files.into_iter().enumerate() {
+        batches[i % associations].push(file);
     }
 
-    if true {
-        info!("Establishing association with '{}'...", &addr);
+    let results: Vec<(Vec<DicomFile>, Result<(), Error>)> = thread::scope(|scope| {
+        let handles: Vec<_> = batches
+            .into_iter()
+            .map(|mut batch| {
+                let tracker = &tracker;
+                scope.spawn(move || {
+                    let result = send_batch(&mut batch, destination, tracker);
+                    (batch, result)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("C-STORE thread panicked"))
+            .collect()
+    });
+
+    let mut first_error = None;
+    let mut any_completed = false;
+    for (batch, result) in results {
+        match result {
+            Ok(()) => any_completed = true,
+            Err(e) => {
+                let message = Report::from_error(&e).to_string();
+                error!("Association with '{}' failed: {}", destination.ae_title, message);
+                for file in batch.iter().filter(|file| !tracker.is_reported(file)) {
+                    tracker.push(file, InstanceStatus::NotSent(message.clone()));
+                }
+                first_error.get_or_insert(e);
+            }
+        }
     }
+    match first_error {
+        Some(e) if !any_completed => Err(e),
+        _ => Ok(tracker.report.into_inner().unwrap()),
+    }
+}
 
-    let mut scu_init = ClientAssociationOptions::new()
-        .calling_ae_title("DCMSHARE")
-        .max_pdu_length(16384);
-
-    for (storage_sop_class_uid, transfer_syntax) in &presentation_contexts {
-        scu_init = scu_init.with_presentation_context(storage_sop_class_uid, vec![transfer_syntax]);
+/// Stores `dicom_files` in a single association with `destination`.
+fn send_batch(dicom_files: &mut [DicomFile], destination: &PacsDestination, report: &Tracker) -> Result<(), Error> {
+    // in proposal order, the context ID of an entry is its index * 2 + 1
+    let mut presentation_contexts: Vec<(String, String)> = vec![];
+    for dicom_file in dicom_files.iter() {
+        let context = (
+            dicom_file.sop_class_uid.to_string(),
+            dicom_file.file_transfer_syntax.clone(),
+        );
+        if !presentation_contexts.contains(&context) {
+            presentation_contexts.push(context);
+        }
     }
 
+    if true {
+        info!("Establishing association with '{}'...", destination.address());
+    }
 
-    let mut scu = scu_init.establish_with(&addr).context(InitScuSnafu)?;
+    let stream = connect(destination)?;
+    let mut scu = AssociationOptions {
+        calling_ae_title: destination.calling_ae_title.clone(),
+        called_ae_title: destination.ae_title.clone(),
+        max_pdu_length: destination.max_pdu_length,
+        max_operations: destination.max_operations,
+        presentation_contexts: presentation_contexts
+            .iter()
+            .map(|(storage_sop_class_uid, transfer_syntax)| {
+                (storage_sop_class_uid.clone(), proposed_transfer_syntaxes(transfer_syntax))
+            })
+            .collect(),
+    }
+    .establish(stream)
+    .context(InitScuSnafu)?;
 
     if true {
-        info!("Association established");
+        info!("Association established, up to {} operations in flight", scu.max_operations());
     }
 
-    for file in &mut dicom_files {
-        // TODO(#106) transfer syntax conversion is currently not supported
-        let r: Result<_, Error> = check_presentation_contexts(file, scu.presentation_contexts())
+    for file in dicom_files.iter_mut() {
+        let r: Result<_, Error> = check_presentation_contexts(file, scu.presentation_contexts(), &presentation_contexts)
             .whatever_context::<_, _>("Could not choose a transfer syntax");
         match r {
             Ok((pc, ts)) => {
@@ -137,32 +296,67 @@ pub fn send(files: Vec<PathBuf>) -> Result<(), Error> {
                 file.ts_selected = Some(ts);
             }
             Err(e) => {
-                error!("{}", Report::from_error(e));
+                let e = Report::from_error(e).to_string();
+                error!("{}: {}", file.file.display(), e);
+                report.push(file, InstanceStatus::NotSent(e));
             }
         }
     }
 
+    match store_files(&mut scu, dicom_files, report) {
+        Ok(()) => scu.release().whatever_context("Failed to release SCU association"),
+        Err(e) => {
+            let _ = scu.abort();
+            Err(e)
+        }
+    }
+}
 
-    for file in dicom_files {
-        if let (Some(pc_selected), Some(ts_uid_selected)) = (file.pc_selected, file.ts_selected) {
-            let cmd = store_req_command(&file.sop_class_uid, &file.sop_instance_uid, 1);
+/// Sends a C-STORE-RQ for every file with a selected presentation context,
+/// keeping up to [`Association::max_operations`] of them outstanding.
+fn store_files(scu: &mut Association, dicom_files: &[DicomFile], report: &Tracker) -> Result<(), Error> {
+    let mut outstanding: HashMap<u16, &DicomFile> = HashMap::new();
+    let mut next_message_id: u16 = 1;
+    for file in dicom_files.iter() {
+        if let (Some(pc_selected), Some(ts_uid_selected)) = (file.pc_selected.clone(), file.ts_selected.clone()) {
+            let message_id = next_message_id;
+            next_message_id = next_message_id.wrapping_add(1).max(1);
+            let cmd = store_req_command(&file.sop_class_uid, &file.sop_instance_uid, message_id);
 
             let mut cmd_data = Vec::with_capacity(128);
             cmd.write_dataset_with_ts(
                 &mut cmd_data,
                 &dicom_transfer_syntax_registry::entries::IMPLICIT_VR_LITTLE_ENDIAN.erased(),
-            );
+            )
+                .whatever_context("Could not write C-STORE-RQ")?;
 
 
             let mut object_data = Vec::with_capacity(2048);
-            let dicom_file =
-                open_file(&file.file).whatever_context("Could not open listed DICOM file")?;
+            let mut dicom_file = match open_file(&file.file) {
+                Ok(dicom_file) => dicom_file,
+                Err(e) => {
+                    error!("Could not open {}: {}", file.file.display(), e);
+                    report.push(file, InstanceStatus::NotSent(format!("could not open file: {}", e)));
+                    continue;
+                }
+            };
             let ts_selected = TransferSyntaxRegistry
                 .get(&ts_uid_selected)
                 .whatever_context("Unsupported file transfer syntax")?;
-            dicom_file
-                .write_dataset_with_ts(&mut object_data, ts_selected)
-                .whatever_context("Could not write object dataset")?;
+            if ts_uid_selected != file.file_transfer_syntax {
+                info!("Transcoding {} from {} to {}", file.file.display(), file.file_transfer_syntax, ts_uid_selected);
+            }
+            if let Err(e) = transcode(&mut dicom_file, ts_selected) {
+                let e = Report::from_error(e).to_string();
+                error!("Could not transcode {}: {}", file.file.display(), e);
+                report.push(file, InstanceStatus::NotSent(e));
+                continue;
+            }
+            if let Err(e) = dicom_file.write_dataset_with_ts(&mut object_data, ts_selected) {
+                error!("Could not write data set of {}: {}", file.file.display(), e);
+                report.push(file, InstanceStatus::NotSent(format!("could not write data set: {}", e)));
+                continue;
+            }
 
             let nbytes = cmd_data.len() + object_data.len();
 
@@ -177,141 +371,256 @@ pub fn send(files: Vec<PathBuf>) -> Result<(), Error> {
                 );
             }
 
-            if nbytes < scu.acceptor_max_pdu_length().saturating_sub(100) as usize {
-                let pdu = Pdu::PData {
-                    data: vec![
-                        PDataValue {
-                            presentation_context_id: pc_selected.id,
-                            value_type: PDataValueType::Command,
-                            is_last: true,
-                            data: cmd_data,
-                        },
-                        PDataValue {
-                            presentation_context_id: pc_selected.id,
-                            value_type: PDataValueType::Data,
-                            is_last: true,
-                            data: object_data,
-                        },
-                    ],
-                };
-
-                scu.send(&pdu)
-                    .whatever_context("Failed to send C-STORE-RQ")?;
-            } else {
-                let pdu = Pdu::PData {
-                    data: vec![PDataValue {
-                        presentation_context_id: pc_selected.id,
-                        value_type: PDataValueType::Command,
-                        is_last: true,
-                        data: cmd_data,
-                    }],
-                };
-
-                scu.send(&pdu)
-                    .whatever_context("Failed to send C-STORE-RQ command")?;
-
-                {
-                    let mut pdata = scu.send_pdata(pc_selected.id);
-                    pdata
-                        .write_all(&object_data)
-                        .whatever_context("Failed to send C-STORE-RQ P-Data")?;
-                }
-            }
-
-            if true {
-                debug!("Awaiting response...");
+            while outstanding.len() >= scu.max_operations() {
+                receive_store_response(scu, &mut outstanding, report)?;
             }
+            scu.send_message(pc_selected.id, cmd_data, Some(object_data))
+                .whatever_context("Failed to send C-STORE-RQ")?;
+            outstanding.insert(message_id, file);
+        }
+    }
 
-            let rsp_pdu = scu
-                .receive()
-                .whatever_context("Failed to receive C-STORE-RSP")?;
-
-            match rsp_pdu {
-                Pdu::PData { data } => {
-                    let data_value = &data[0];
-
-                    let cmd_obj = InMemDicomObject::read_dataset_with_ts(
-                        &data_value.data[..],
-                        &dicom_transfer_syntax_registry::entries::IMPLICIT_VR_LITTLE_ENDIAN
-                            .erased(),
-                    )
-                        .whatever_context("Could not read response from SCP")?;
-                    if true {
-                        debug!("Full response: {:?}", cmd_obj);
-                    }
-                    let status = cmd_obj
-                        .element(tags::STATUS)
-                        .whatever_context("Could not find status code in response")?
-                        .to_int::<u16>()
-                        .whatever_context("Status code in response is not a valid integer")?;
-                    let storage_sop_instance_uid = file
-                        .sop_instance_uid
-                        .trim_end_matches(|c: char| c.is_whitespace() || c == '\0');
-
-                    match status {
-                        // Success
-                        0 => {
-                            if true {
-                                info!("Successfully stored instance {}", storage_sop_instance_uid);
-                            }
-                        }
-                        // Warning
-                        1 | 0x0107 | 0x0116 | 0xB000..=0xBFFF => {
-                            warn!(
-                                "Possible issue storing instance `{}` (status code {:04X}H)",
-                                storage_sop_instance_uid, status
-                            );
-                        }
-                        0xFF00 | 0xFF01 => {
-                            warn!(
-                                "Possible issue storing instance `{}`: status is pending (status code {:04X}H)",
-                                storage_sop_instance_uid, status
-                            );
-                        }
-                        0xFE00 => {
-                            error!(
-                                "Could not store instance `{}`: operation cancelled",
-                                storage_sop_instance_uid
-                            );
-                            if false {
-                                let _ = scu.abort();
-                                std::process::exit(-2);
-                            }
-                        }
-                        _ => {
-                            error!(
-                                "Failed to store instance `{}` (status code {:04X}H)",
-                                storage_sop_instance_uid, status
-                            );
-                            if false {
-                                let _ = scu.abort();
-                                std::process::exit(-2);
-                            }
-                        }
-                    }
-                }
+    if true {
+        debug!("Awaiting {} responses...", outstanding.len());
+    }
+    while !outstanding.is_empty() {
+        receive_store_response(scu, &mut outstanding, report)?;
+    }
+    Ok(())
+}
 
-                pdu @ Pdu::Unknown { .. }
-                | pdu @ Pdu::AssociationRQ { .. }
-                | pdu @ Pdu::AssociationAC { .. }
-                | pdu @ Pdu::AssociationRJ { .. }
-                | pdu @ Pdu::ReleaseRQ
-                | pdu @ Pdu::ReleaseRP
-                | pdu @ Pdu::AbortRQ { .. } => {
-                    error!("Unexpected SCP response: {:?}", pdu);
-                    let _ = scu.abort();
-                    std::process::exit(-2);
-                }
+/// Receives the response to one of the `outstanding` C-STORE-RQs and reports
+/// the outcome of its instance.
+fn receive_store_response(
+    scu: &mut Association,
+    outstanding: &mut HashMap<u16, &DicomFile>,
+    report: &Tracker,
+) -> Result<(), Error> {
+    let (message_id, cmd_obj) = receive_response(scu)?;
+    if true {
+        debug!("Full response: {:?}", cmd_obj);
+    }
+    let Some(file) = outstanding.remove(&message_id) else {
+        warn!("Ignoring response to unknown message {}", message_id);
+        return Ok(());
+    };
+    let status = cmd_obj
+        .element(tags::STATUS)
+        .whatever_context("Could not find status code in response")?
+        .to_int::<u16>()
+        .whatever_context("Status code in response is not a valid integer")?;
+    let storage_sop_instance_uid = file
+        .sop_instance_uid
+        .trim_end_matches(|c: char| c.is_whitespace() || c == '\0');
+
+    match status {
+        // Success
+        0 => {
+            if true {
+                info!("Successfully stored instance {}", storage_sop_instance_uid);
             }
+            report.push(file, InstanceStatus::Stored);
+        }
+        // Warning
+        1 | 0x0107 | 0x0116 | 0xB000..=0xBFFF => {
+            warn!(
+                "Possible issue storing instance `{}` (status code {:04X}H)",
+                storage_sop_instance_uid, status
+            );
+            report.push(file, InstanceStatus::Warning(status));
+        }
+        0xFF00 | 0xFF01 => {
+            warn!(
+                "Possible issue storing instance `{}`: status is pending (status code {:04X}H)",
+                storage_sop_instance_uid, status
+            );
+            report.push(file, InstanceStatus::Warning(status));
+        }
+        0xFE00 => {
+            error!(
+                "Could not store instance `{}`: operation cancelled",
+                storage_sop_instance_uid
+            );
+            report.push(file, InstanceStatus::Failed(status));
+        }
+        _ => {
+            error!(
+                "Failed to store instance `{}` (status code {:04X}H)",
+                storage_sop_instance_uid, status
+            );
+            report.push(file, InstanceStatus::Failed(status));
         }
     }
+    Ok(())
+}
 
+/// Runs [`send`] on the blocking thread pool, progress is reported on
+/// `progress` as long as the receiver is alive.
+pub async fn send_async(
+    files: Vec<PathBuf>,
+    destination: PacsDestination,
+    progress: mpsc::UnboundedSender<SendProgress>,
+) -> Result<SendReport, Error> {
+    tokio::task::spawn_blocking(move || {
+        send(files, &destination, &|event| {
+            let _ = progress.send(event);
+        })
+    })
+        .await
+        .whatever_context("C-STORE task failed")?
+}
 
+/// Verifies the connection to `destination` with a C-ECHO.
+pub fn echo(destination: &PacsDestination) -> Result<(), Error> {
+    let addr = destination.address();
+    info!("Establishing association with '{}'...", &addr);
+    let stream = connect(destination)?;
+    let mut scu = AssociationOptions {
+        calling_ae_title: destination.calling_ae_title.clone(),
+        called_ae_title: destination.ae_title.clone(),
+        max_pdu_length: destination.max_pdu_length,
+        max_operations: 1,
+        presentation_contexts: vec![(
+            VERIFICATION.to_string(),
+            vec![dicom_transfer_syntax_registry::entries::IMPLICIT_VR_LITTLE_ENDIAN.uid().to_string()],
+        )],
+    }
+    .establish(stream)
+    .context(InitScuSnafu)?;
+    let pc = scu
+        .presentation_contexts()
+        .first()
+        .whatever_context("Verification SOP Class not accepted")?
+        .clone();
+
+    let cmd = echo_req_command(1);
+    let mut cmd_data = Vec::with_capacity(128);
+    cmd.write_dataset_with_ts(
+        &mut cmd_data,
+        &dicom_transfer_syntax_registry::entries::IMPLICIT_VR_LITTLE_ENDIAN.erased(),
+    )
+        .whatever_context("Could not write C-ECHO-RQ")?;
+    let response = scu
+        .send_message(pc.id, cmd_data, None)
+        .whatever_context("Failed to send C-ECHO-RQ")
+        .and_then(|_| receive_response(&mut scu));
+    let cmd_obj = match response {
+        Ok((1, cmd_obj)) => cmd_obj,
+        Ok((message_id, _)) => {
+            let _ = scu.abort();
+            return UnexpectedResponseSnafu { pdu: format!("response to message {}", message_id) }.fail();
+        }
+        Err(e) => {
+            let _ = scu.abort();
+            return Err(e);
+        }
+    };
+    let status = cmd_obj
+        .element(tags::STATUS)
+        .whatever_context("Could not find status code in response")?
+        .to_int::<u16>()
+        .whatever_context("Status code in response is not a valid integer")?;
     scu.release()
         .whatever_context("Failed to release SCU association")?;
+    ensure_whatever!(status == 0, "C-ECHO failed (status code {:04X}H)", status);
+    info!("C-ECHO to '{}' successful", &addr);
     Ok(())
 }
 
+/// Receives the next response command and the ID of the message it responds
+/// to.
+///
+/// The command may be split into several P-DATA fragments, spread over more
+/// than one PDU.
+fn receive_response(
+    scu: &mut Association,
+) -> Result<(u16, InMemDicomObject<StandardDataDictionary>), Error> {
+    let mut cmd_data = Vec::new();
+    loop {
+        let pdu = scu.receive().whatever_context("Failed to receive response")?;
+        let Pdu::PData { data } = pdu else {
+            return UnexpectedResponseSnafu { pdu: pdu.short_description() }.fail();
+        };
+        let mut complete = false;
+        for data_value in data {
+            if data_value.value_type != PDataValueType::Command {
+                debug!("Ignoring data set fragment in response");
+                continue;
+            }
+            cmd_data.extend_from_slice(&data_value.data);
+            complete = data_value.is_last;
+        }
+        if complete {
+            break;
+        }
+    }
+
+    let cmd_obj = InMemDicomObject::read_dataset_with_ts(
+        &cmd_data[..],
+        &dicom_transfer_syntax_registry::entries::IMPLICIT_VR_LITTLE_ENDIAN.erased(),
+    )
+        .whatever_context("Could not read response from SCP")?;
+    let responded_to = cmd_obj
+        .element(tags::MESSAGE_ID_BEING_RESPONDED_TO)
+        .whatever_context("Could not find message ID being responded to in response")?
+        .to_int::<u16>()
+        .whatever_context("Message ID being responded to is not a valid integer")?;
+    Ok((responded_to, cmd_obj))
+}
+
+/// Opens the connection to `destination`, over TLS if the destination
+/// requires it.
+fn connect(destination: &PacsDestination) -> Result<Box<dyn Transport>, Error> {
+    // Whatever is not Send, keep only the message of TLS errors
+    let connector = match destination.tls.connector() {
+        Ok(connector) => connector,
+        Err(e) => whatever!("Invalid TLS configuration: {}", Report::from_error(e)),
+    };
+    match connector {
+        Some(connector) => match tls::connect(&connector, &destination.host, destination.port) {
+            Ok(stream) => Ok(Box::new(stream)),
+            Err(e) => whatever!("Could not open TLS connection: {}", Report::from_error(e)),
+        },
+        None => {
+            let stream = TcpStream::connect((destination.host.as_str(), destination.port))
+                .with_whatever_context(|_| format!("Could not connect to {}", destination.address()))?;
+            Ok(Box::new(stream))
+        }
+    }
+}
+
+fn echo_req_command(message_id: u16) -> InMemDicomObject<StandardDataDictionary> {
+    InMemDicomObject::from_element_iter([
+        // SOP Class UID
+        DataElement::new(
+            tags::AFFECTED_SOP_CLASS_UID,
+            VR::UI,
+            dicom_value!(Str, VERIFICATION),
+        ),
+
+        // command field
+        DataElement::new(
+            tags::COMMAND_FIELD,
+            VR::US,
+            dicom_value!(U16, [0x0030]),
+        ),
+
+        // message ID
+        DataElement::new(
+            tags::MESSAGE_ID,
+            VR::US,
+            dicom_value!(U16, [message_id]),
+        ),
+
+        // data set type: no data set
+        DataElement::new(
+            tags::COMMAND_DATA_SET_TYPE,
+            VR::US,
+            dicom_value!(U16, [0x0101]),
+        ),
+    ])
+}
+
 fn store_req_command(
     storage_sop_class_uid: &str,
     storage_sop_instance_uid: &str,
@@ -336,7 +645,7 @@ fn store_req_command(
         DataElement::new(
             tags::MESSAGE_ID,
             VR::US,
-            dicom_value!(U16, [1]),
+            dicom_value!(U16, [message_id]),
         ),
 
         //priority
@@ -390,33 +699,41 @@ fn check_file(file: &Path) -> Result<DicomFile, Error> {
     })
 }
 
+/// Chooses the accepted presentation context proposed for the file's SOP
+/// class and transfer syntax, preferring the file's own transfer syntax over
+/// one that requires transcoding.
 fn check_presentation_contexts(
     file: &DicomFile,
     pcs: &[dicom_ul::pdu::PresentationContextResult],
+    proposed: &[(String, String)],
 ) -> Result<(dicom_ul::pdu::PresentationContextResult, String), Error> {
     let file_ts = TransferSyntaxRegistry
         .get(&file.file_transfer_syntax)
         .whatever_context("Unsupported file transfer syntax")?;
-    // TODO(#106) transfer syntax conversion is currently not supported
-    let pc = pcs
+    let candidates: Vec<_> = pcs
         .iter()
-        .find(|pc| {
-            // Check support for this transfer syntax.
-            // If it is the same as the file, we're good.
-            // Otherwise, uncompressed data set encoding
-            // and native pixel data is required on both ends.
-            let ts = &pc.transfer_syntax;
-            ts == file_ts.uid()
-                || TransferSyntaxRegistry
-                .get(&pc.transfer_syntax)
-                .filter(|ts| file_ts.is_codec_free() && ts.is_codec_free())
-                .map(|_| true)
+        .filter(|pc| {
+            proposed
+                .get((pc.id as usize).wrapping_sub(1) / 2)
+                .map(|(sop_class_uid, ts)| *sop_class_uid == file.sop_class_uid && *ts == file.file_transfer_syntax)
                 .unwrap_or(false)
         })
+        .collect();
+    let pc = candidates
+        .iter()
+        .find(|pc| pc.transfer_syntax.trim_end_matches('\0') == file_ts.uid())
+        .or_else(|| {
+            candidates.iter().find(|pc| {
+                TransferSyntaxRegistry
+                    .get(pc.transfer_syntax.trim_end_matches('\0'))
+                    .map(|ts| can_transcode(file_ts, ts))
+                    .unwrap_or(false)
+            })
+        })
         .whatever_context("No presentation context accepted")?;
     let ts = TransferSyntaxRegistry
-        .get(&pc.transfer_syntax)
+        .get(pc.transfer_syntax.trim_end_matches('\0'))
         .whatever_context("Poorly negotiated transfer syntax")?;
 
-    Ok((pc.clone(), String::from(ts.uid())))
+    Ok(((*pc).clone(), String::from(ts.uid())))
 }
@@ -0,0 +1,223 @@
+//! DICOM TLS secure transport connection profile (PS3.15 B.1).
+//!
+//! `dicom_ul`'s server association only speaks over plain `TcpStream`s, so
+//! TLS of accepted connections is terminated here and the decrypted traffic
+//! is relayed through a loopback connection. Outbound associations run over
+//! the TLS stream directly.
+
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::pin::Pin;
+
+use openssl::ssl::{Ssl, SslAcceptor, SslConnector, SslFiletype, SslMethod, SslStream, SslVerifyMode};
+use serde_derive::Deserialize;
+use snafu::{ensure_whatever, whatever, OptionExt, ResultExt, Whatever};
+use tokio::io::copy_bidirectional;
+use tokio::runtime::Handle;
+use tracing::debug;
+
+/// `tls` table of the `[dicom]` and `[pacs]` configuration sections.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    /// PEM certificate chain presented to the peer, required for the listener.
+    pub certificate: Option<PathBuf>,
+    /// PEM private key of `certificate`.
+    pub private_key: Option<PathBuf>,
+    /// PEM trust anchors used to verify the peer's certificate, the system
+    /// trust store if unset.
+    pub ca_certificates: Option<PathBuf>,
+    /// Listener only: refuse clients without a valid certificate.
+    pub require_client_certificate: bool,
+}
+
+impl TlsConfig {
+    /// TLS acceptor for the Store SCP, `None` if TLS is disabled.
+    pub fn acceptor(&self) -> Result<Option<SslAcceptor>, Whatever> {
+        if !self.enabled {
+            return Ok(None);
+        }
+        let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())
+            .whatever_context("could not create TLS acceptor")?;
+        let certificate = self.certificate.as_ref().whatever_context("TLS listener requires a certificate")?;
+        let private_key = self.private_key.as_ref().whatever_context("TLS listener requires a private key")?;
+        builder
+            .set_certificate_chain_file(certificate)
+            .with_whatever_context(|_| format!("could not load certificate {}", certificate.display()))?;
+        builder
+            .set_private_key_file(private_key, SslFiletype::PEM)
+            .with_whatever_context(|_| format!("could not load private key {}", private_key.display()))?;
+        builder
+            .check_private_key()
+            .whatever_context("private key does not match certificate")?;
+        if let Some(ca_certificates) = &self.ca_certificates {
+            builder
+                .set_ca_file(ca_certificates)
+                .with_whatever_context(|_| format!("could not load trust anchors {}", ca_certificates.display()))?;
+        }
+        if self.require_client_certificate {
+            ensure_whatever!(self.ca_certificates.is_some(), "client certificate authentication requires ca_certificates");
+            builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        }
+        Ok(Some(builder.build()))
+    }
+
+    /// TLS connector for outbound associations, `None` if TLS is disabled.
+    pub fn connector(&self) -> Result<Option<SslConnector>, Whatever> {
+        if !self.enabled {
+            return Ok(None);
+        }
+        let mut builder = SslConnector::builder(SslMethod::tls())
+            .whatever_context("could not create TLS connector")?;
+        if let Some(ca_certificates) = &self.ca_certificates {
+            builder
+                .set_ca_file(ca_certificates)
+                .with_whatever_context(|_| format!("could not load trust anchors {}", ca_certificates.display()))?;
+        }
+        match (&self.certificate, &self.private_key) {
+            (Some(certificate), Some(private_key)) => {
+                builder
+                    .set_certificate_chain_file(certificate)
+                    .with_whatever_context(|_| format!("could not load certificate {}", certificate.display()))?;
+                builder
+                    .set_private_key_file(private_key, SslFiletype::PEM)
+                    .with_whatever_context(|_| format!("could not load private key {}", private_key.display()))?;
+                builder
+                    .check_private_key()
+                    .whatever_context("private key does not match certificate")?;
+            }
+            (None, None) => {}
+            _ => whatever!("client certificate and private key must be configured together"),
+        }
+        Ok(Some(builder.build()))
+    }
+}
+
+/// Performs the server side TLS handshake on `stream` and returns a
+/// loopback stream carrying the decrypted traffic. The relay runs on the
+/// current tokio runtime, call this from a blocking task.
+pub fn accept(acceptor: &SslAcceptor, stream: TcpStream) -> Result<TcpStream, Whatever> {
+    let runtime = Handle::try_current().whatever_context("TLS relay requires a tokio runtime")?;
+    let peer = stream.peer_addr().ok();
+    let ssl = Ssl::new(acceptor.context()).whatever_context("could not create TLS session")?;
+    let (plain, relay_end) = loopback_pair()?;
+    stream.set_nonblocking(true).whatever_context("could not configure TLS socket")?;
+    relay_end.set_nonblocking(true).whatever_context("could not configure loopback socket")?;
+    let (mut tls_stream, mut relay_end) = runtime.block_on(async {
+        let stream = tokio::net::TcpStream::from_std(stream).whatever_context("could not register TLS socket")?;
+        let relay_end = tokio::net::TcpStream::from_std(relay_end).whatever_context("could not register loopback socket")?;
+        let mut tls_stream = tokio_openssl::SslStream::new(ssl, stream).whatever_context("could not create TLS session")?;
+        if let Err(e) = Pin::new(&mut tls_stream).accept().await {
+            whatever!("TLS handshake with {:?} failed: {}", peer, e);
+        }
+        Ok::<_, Whatever>((tls_stream, relay_end))
+    })?;
+    runtime.spawn(async move {
+        match copy_bidirectional(&mut tls_stream, &mut relay_end).await {
+            Ok((received, sent)) => debug!("TLS connection with {:?} closed, {} bytes received, {} sent", peer, received, sent),
+            Err(e) => debug!("TLS connection with {:?} closed: {}", peer, e),
+        }
+    });
+    Ok(plain)
+}
+
+/// Opens a TLS connection to `host:port` an association can be established
+/// over.
+pub fn connect(connector: &SslConnector, host: &str, port: u16) -> Result<SslStream<TcpStream>, Whatever> {
+    let stream = TcpStream::connect((host, port))
+        .with_whatever_context(|_| format!("could not connect to {}:{}", host, port))?;
+    match connector.connect(host, stream) {
+        Ok(tls_stream) => Ok(tls_stream),
+        Err(e) => whatever!("TLS handshake with {}:{} failed: {}", host, port, e),
+    }
+}
+
+/// Connects a loopback pair, accepting only our own end of it.
+fn loopback_pair() -> Result<(TcpStream, TcpStream), Whatever> {
+    let listener = TcpListener::bind("127.0.0.1:0").whatever_context("could not bind loopback relay")?;
+    let addr = listener.local_addr().whatever_context("could not bind loopback relay")?;
+    let plain = TcpStream::connect(addr).whatever_context("could not connect loopback relay")?;
+    let local_addr = plain.local_addr().whatever_context("could not connect loopback relay")?;
+    loop {
+        let (relay_end, peer) = listener.accept().whatever_context("could not accept loopback relay")?;
+        if peer == local_addr {
+            return Ok((plain, relay_end));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::{X509NameBuilder, X509};
+    use std::io::{Read, Write};
+
+    /// Self-signed certificate for `localhost`, returns the certificate and
+    /// key paths.
+    fn certificate(dir: &std::path::Path) -> (PathBuf, PathBuf) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        let san = SubjectAlternativeName::new().dns("localhost").build(&builder.x509v3_context(None, None)).unwrap();
+        builder.append_extension(san).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        let certificate_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        std::fs::write(&certificate_path, builder.build().to_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        (certificate_path, key_path)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn relays_accepted_connections_in_both_directions() {
+        let dir = tempfile::tempdir().unwrap();
+        let (certificate, private_key) = certificate(dir.path());
+        let server = TlsConfig {
+            enabled: true,
+            certificate: Some(certificate.clone()),
+            private_key: Some(private_key),
+            ..TlsConfig::default()
+        };
+        let client = TlsConfig {
+            enabled: true,
+            ca_certificates: Some(certificate),
+            ..TlsConfig::default()
+        };
+        let acceptor = server.acceptor().unwrap().unwrap();
+        let connector = client.connector().unwrap().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = tokio::task::spawn_blocking(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut plain = accept(&acceptor, stream).unwrap();
+            let mut request = vec![0u8; 100_000];
+            plain.read_exact(&mut request).unwrap();
+            plain.write_all(&request.iter().map(|b| b.wrapping_add(1)).collect::<Vec<u8>>()).unwrap();
+        });
+        let client = tokio::task::spawn_blocking(move || {
+            let mut tls_stream = connect(&connector, "localhost", port).unwrap();
+            let request: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+            tls_stream.write_all(&request).unwrap();
+            let mut response = vec![0u8; request.len()];
+            tls_stream.read_exact(&mut response).unwrap();
+            assert!(response.iter().zip(&request).all(|(r, q)| *r == q.wrapping_add(1)));
+        });
+        server.await.unwrap();
+        client.await.unwrap();
+    }
+}
@@ -0,0 +1,194 @@
+//! Conversion of instances to a transfer syntax accepted by the PACS.
+//!
+//! Encapsulated pixel data is decoded through the pixel data adapters of the
+//! transfer syntax registry. Only native (uncompressed) target syntaxes are
+//! supported, the registry provides no encoders.
+
+use dicom_core::{dicom_value, DataElement, PrimitiveValue, VR};
+use dicom_dictionary_std::tags;
+use dicom_encoding::transfer_syntax::{Codec, TransferSyntax, TransferSyntaxIndex};
+use dicom_object::{mem::InMemDicomObject, FileDicomObject};
+use dicom_transfer_syntax_registry::{entries, TransferSyntaxRegistry};
+use snafu::{ensure_whatever, whatever, OptionExt, ResultExt, Whatever};
+
+/// Transfer syntaxes proposed in addition to the file's own one.
+pub const FALLBACK_TRANSFER_SYNTAXES: [&str; 2] = [
+    entries::EXPLICIT_VR_LITTLE_ENDIAN.uid(),
+    entries::IMPLICIT_VR_LITTLE_ENDIAN.uid(),
+];
+
+/// The file's own transfer syntax followed by the native fallbacks it can be
+/// converted to.
+pub fn proposed_transfer_syntaxes(file_ts_uid: &str) -> Vec<String> {
+    let mut proposed = vec![file_ts_uid.to_string()];
+    let Some(file_ts) = TransferSyntaxRegistry.get(file_ts_uid) else { return proposed };
+    for fallback in FALLBACK_TRANSFER_SYNTAXES {
+        let fallback_ts = TransferSyntaxRegistry.get(fallback).unwrap();
+        if fallback != file_ts_uid && can_transcode(file_ts, fallback_ts) {
+            proposed.push(fallback.to_string());
+        }
+    }
+    proposed
+}
+
+/// Whether a file in `file_ts` can be sent with `target_ts`.
+pub fn can_transcode(file_ts: &TransferSyntax, target_ts: &TransferSyntax) -> bool {
+    file_ts.uid() == target_ts.uid()
+        || (target_ts.is_codec_free()
+            && matches!(file_ts.codec(), Codec::None | Codec::PixelData(_)))
+}
+
+/// Decodes the pixel data of `obj` if its transfer syntax cannot be written
+/// as `target_ts` directly. The data set can be written with `target_ts`
+/// afterwards.
+///
+/// Decoding reproduces the stored pixel values, so the SOP Instance UID and
+/// Derivation Description are kept. Lossy Image Compression is recorded
+/// because the lossy history is no longer visible from the transfer syntax.
+pub fn transcode(obj: &mut FileDicomObject<InMemDicomObject>, target_ts: &TransferSyntax) -> Result<(), Whatever> {
+    let file_ts_uid = obj.meta().transfer_syntax.trim_end_matches('\0').to_string();
+    let file_ts = TransferSyntaxRegistry
+        .get(&file_ts_uid)
+        .with_whatever_context(|| format!("Unsupported file transfer syntax {}", file_ts_uid))?;
+    if file_ts.uid() == target_ts.uid() {
+        return Ok(());
+    }
+    ensure_whatever!(target_ts.is_codec_free(), "Encoding into {} is not supported", target_ts.name());
+
+    let adapter = match file_ts.codec() {
+        Codec::None => {
+            obj.meta_mut().set_transfer_syntax(target_ts);
+            return Ok(());
+        }
+        Codec::PixelData(adapter) => adapter,
+        _ => whatever!("No pixel data decoder for {}", file_ts.name()),
+    };
+    let mut pixel_data = Vec::new();
+    adapter
+        .decode(&*obj, &mut pixel_data)
+        .with_whatever_context(|_| format!("Could not decode {} pixel data", file_ts.name()))?;
+    if pixel_data.len() % 2 != 0 {
+        pixel_data.push(0);
+    }
+
+    let bits_allocated = obj.element(tags::BITS_ALLOCATED)
+        .whatever_context("Missing Bits Allocated")?
+        .to_int::<u16>()
+        .whatever_context("Invalid Bits Allocated")?;
+    let samples_per_pixel = obj.element(tags::SAMPLES_PER_PIXEL)
+        .whatever_context("Missing Samples per Pixel")?
+        .to_int::<u16>()
+        .whatever_context("Invalid Samples per Pixel")?;
+    let vr = if bits_allocated > 8 { VR::OW } else { VR::OB };
+    obj.put(DataElement::new(tags::PIXEL_DATA, vr, PrimitiveValue::from(pixel_data)));
+
+    if samples_per_pixel > 1 {
+        // The RLE decoder keeps the segments as colour planes, the JPEG
+        // decoder returns interleaved RGB samples
+        if file_ts.uid() == entries::RLE_LOSSLESS.uid() {
+            obj.put(DataElement::new(tags::PLANAR_CONFIGURATION, VR::US, dicom_value!(U16, [1])));
+        } else {
+            obj.put(DataElement::new(tags::PLANAR_CONFIGURATION, VR::US, dicom_value!(U16, [0])));
+            obj.put(DataElement::new(tags::PHOTOMETRIC_INTERPRETATION, VR::CS, dicom_value!(Str, "RGB")));
+        }
+    }
+    if is_lossy(file_ts) {
+        record_lossy_compression(obj);
+    }
+    obj.meta_mut().set_transfer_syntax(target_ts);
+    Ok(())
+}
+
+fn is_lossy(ts: &TransferSyntax) -> bool {
+    ts.uid() == entries::JPEG_BASELINE.uid() || ts.uid() == entries::JPEG_EXTENDED.uid()
+}
+
+fn record_lossy_compression(obj: &mut InMemDicomObject) {
+    obj.put(DataElement::new(tags::LOSSY_IMAGE_COMPRESSION, VR::CS, dicom_value!(Str, "01")));
+    if obj.element(tags::LOSSY_IMAGE_COMPRESSION_METHOD).is_err() {
+        obj.put(DataElement::new(tags::LOSSY_IMAGE_COMPRESSION_METHOD, VR::CS, dicom_value!(Str, "ISO_10918_1")));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dicom_object::meta::FileMetaTableBuilder;
+
+    const JPEG_2000: &str = "1.2.840.10008.1.2.4.91";
+
+    fn ts(uid: &str) -> &'static TransferSyntax {
+        TransferSyntaxRegistry.get(uid).unwrap()
+    }
+
+    fn image(ts_uid: &str, pixel_data: Vec<u8>) -> FileDicomObject<InMemDicomObject> {
+        let mut obj = InMemDicomObject::new_empty();
+        obj.put(DataElement::new(tags::ROWS, VR::US, dicom_value!(U16, [2])));
+        obj.put(DataElement::new(tags::COLUMNS, VR::US, dicom_value!(U16, [2])));
+        obj.put(DataElement::new(tags::BITS_ALLOCATED, VR::US, dicom_value!(U16, [8])));
+        obj.put(DataElement::new(tags::SAMPLES_PER_PIXEL, VR::US, dicom_value!(U16, [1])));
+        obj.put(DataElement::new(tags::PIXEL_DATA, VR::OB, PrimitiveValue::from(pixel_data)));
+        obj.with_meta(
+            FileMetaTableBuilder::new()
+                .media_storage_sop_class_uid("1.2.840.10008.5.1.4.1.1.7")
+                .media_storage_sop_instance_uid("1.2.3.4")
+                .transfer_syntax(ts_uid),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn transcodes_between_native_syntaxes_only() {
+        let explicit = entries::EXPLICIT_VR_LITTLE_ENDIAN.uid();
+        let implicit = entries::IMPLICIT_VR_LITTLE_ENDIAN.uid();
+        let jpeg = entries::JPEG_BASELINE.uid();
+        assert!(can_transcode(ts(explicit), ts(implicit)));
+        assert!(can_transcode(ts(implicit), ts(explicit)));
+        assert!(can_transcode(ts(jpeg), ts(explicit)));
+        assert!(can_transcode(ts(jpeg), ts(jpeg)));
+        assert!(!can_transcode(ts(explicit), ts(jpeg)));
+        assert!(!can_transcode(ts(JPEG_2000), ts(explicit)));
+    }
+
+    #[test]
+    fn proposes_the_fallbacks_the_file_can_be_converted_to() {
+        let explicit = entries::EXPLICIT_VR_LITTLE_ENDIAN.uid();
+        let implicit = entries::IMPLICIT_VR_LITTLE_ENDIAN.uid();
+        let jpeg = entries::JPEG_BASELINE.uid();
+        assert_eq!(proposed_transfer_syntaxes(explicit), vec![explicit, implicit]);
+        assert_eq!(proposed_transfer_syntaxes(jpeg), vec![jpeg, explicit, implicit]);
+        assert_eq!(proposed_transfer_syntaxes(JPEG_2000), vec![JPEG_2000]);
+        assert_eq!(proposed_transfer_syntaxes("1.2.3"), vec!["1.2.3"]);
+    }
+
+    #[test]
+    fn keeps_the_pixel_data_of_native_files() {
+        let mut obj = image(entries::EXPLICIT_VR_LITTLE_ENDIAN.uid(), vec![1, 2, 3, 4]);
+        transcode(&mut obj, ts(entries::IMPLICIT_VR_LITTLE_ENDIAN.uid())).unwrap();
+        assert_eq!(obj.meta().transfer_syntax().trim_end_matches('\0'), entries::IMPLICIT_VR_LITTLE_ENDIAN.uid());
+        assert_eq!(obj.element(tags::PIXEL_DATA).unwrap().to_bytes().unwrap().as_ref(), &[1, 2, 3, 4]);
+        assert!(obj.element(tags::LOSSY_IMAGE_COMPRESSION).is_err());
+    }
+
+    #[test]
+    fn refuses_encapsulated_files_without_decoder() {
+        let mut obj = image(JPEG_2000, vec![1, 2, 3, 4]);
+        assert!(transcode(&mut obj, ts(entries::EXPLICIT_VR_LITTLE_ENDIAN.uid())).is_err());
+        assert_eq!(obj.meta().transfer_syntax().trim_end_matches('\0'), JPEG_2000);
+
+        let mut obj = image(entries::EXPLICIT_VR_LITTLE_ENDIAN.uid(), vec![1, 2, 3, 4]);
+        assert!(transcode(&mut obj, ts(entries::JPEG_BASELINE.uid())).is_err());
+    }
+
+    #[test]
+    fn records_lossy_compression() {
+        let mut obj = InMemDicomObject::new_empty();
+        record_lossy_compression(&mut obj);
+        assert_eq!(obj.element(tags::LOSSY_IMAGE_COMPRESSION).unwrap().to_str().unwrap(), "01");
+        assert_eq!(obj.element(tags::LOSSY_IMAGE_COMPRESSION_METHOD).unwrap().to_str().unwrap(), "ISO_10918_1");
+
+        obj.put(DataElement::new(tags::LOSSY_IMAGE_COMPRESSION_METHOD, VR::CS, dicom_value!(Str, "ISO_14495_1")));
+        record_lossy_compression(&mut obj);
+        assert_eq!(obj.element(tags::LOSSY_IMAGE_COMPRESSION_METHOD).unwrap().to_str().unwrap(), "ISO_14495_1");
+    }
+}
@@ -0,0 +1,718 @@
+//! Durable queue of the work which depends on the object store, the Matrix
+//! homeserver or the PACS, so that neither being unavailable nor a restart
+//! loses a study.
+//!
+//! Jobs are rows of the `jobs` table of the study database, their tasks
+//! encrypted with the database key. Every operation updates the rows it
+//! concerns in one transaction, so `dcmshare jobs` commands take effect on a
+//! running gateway without undoing its updates.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use rusqlite::{params, Connection, Params};
+use serde_derive::{Deserialize, Serialize};
+use snafu::{whatever, OptionExt, ResultExt, Whatever};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::Notify;
+use tracing::{error, warn};
+use uuid::Uuid;
+
+use crate::config::JobsConfig;
+use crate::dcm::storescp::StoredInstance;
+use crate::matrix::client::Import;
+use crate::studies::db::StudyDb;
+use crate::studies::manifest::unix_time;
+use crate::studies::store::Study;
+
+/// Interval due jobs are looked for when no job was queued, which also
+/// picks up jobs retried from the command line.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Task {
+    /// Encrypt and upload a received instance
+    Upload(StoredInstance),
+    /// Upload the manifest of the instances of a study uploaded so far, due
+    /// once no further instance was uploaded for the quiet period
+    Publish(Study),
+    /// Announce a study whose manifest was uploaded in the Matrix rooms
+    Notify(Study),
+    /// Import a study shared in a Matrix room into the local PACS
+    Import(Import),
+}
+
+impl Task {
+    fn kind(&self) -> &'static str {
+        match self {
+            Task::Upload(_) => "upload",
+            Task::Publish(_) => "publish",
+            Task::Notify(_) => "notify",
+            Task::Import(_) => "import",
+        }
+    }
+
+    /// Study the task belongs to, if it is known without decrypting it.
+    fn study_instance_uid(&self) -> Option<&str> {
+        match self {
+            Task::Upload(instance) => Some(&instance.study_instance_uid).filter(|uid| !uid.is_empty()).map(String::as_str),
+            Task::Publish(study) | Task::Notify(study) => Some(&study.study_instance_uid),
+            Task::Import(_) => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    /// Waiting until `next_attempt_at`
+    Pending,
+    /// Handed to a worker, pending again after a restart
+    Running,
+    /// Failed `max_attempts` times, only retried on request
+    Dead,
+    /// Publication waiting for the queued uploads of its study
+    Waiting,
+}
+
+impl JobState {
+    fn as_str(self) -> &'static str {
+        match self {
+            JobState::Pending => "pending",
+            JobState::Running => "running",
+            JobState::Dead => "dead",
+            JobState::Waiting => "waiting",
+        }
+    }
+
+    fn parse(state: &str) -> Result<JobState, Whatever> {
+        Ok(match state {
+            "pending" => JobState::Pending,
+            "running" => JobState::Running,
+            "dead" => JobState::Dead,
+            "waiting" => JobState::Waiting,
+            _ => whatever!("unknown job state {:?}", state),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Job {
+    pub id: String,
+    pub task: Task,
+    pub state: JobState,
+    /// Failed attempts so far
+    pub attempts: u32,
+    /// Seconds since the Unix epoch the next attempt is due
+    pub next_attempt_at: u64,
+    pub last_error: Option<String>,
+    pub created_at: u64,
+}
+
+impl Job {
+    fn new(task: Task, next_attempt_at: u64) -> Job {
+        Job {
+            id: Uuid::new_v4().to_string(),
+            task,
+            state: JobState::Pending,
+            attempts: 0,
+            next_attempt_at,
+            last_error: None,
+            created_at: unix_time(),
+        }
+    }
+
+    /// Short description for logs and job listings.
+    pub fn describe(&self) -> String {
+        match &self.task {
+            Task::Upload(instance) => format!("upload of instance {}", instance.sop_instance_uid),
+            Task::Publish(study) => format!("manifest of study {}", study.study_instance_uid),
+            Task::Notify(study) => format!("notification of study {}", study.study_instance_uid),
+            Task::Import(import) => format!("import of study {}", import.study_id),
+        }
+    }
+}
+
+/// Columns [`JobQueue::select`] reads a job from.
+const JOB_COLUMNS: &str = "id, encrypted_task, state, attempts, next_attempt_at, last_error, created_at";
+
+#[derive(Clone)]
+pub struct JobQueue {
+    db: StudyDb,
+    config: JobsConfig,
+    wakeup: Arc<Notify>,
+}
+
+impl JobQueue {
+    /// Opens the queue in the study database.
+    pub fn open(db: StudyDb, config: &JobsConfig) -> JobQueue {
+        JobQueue {
+            db,
+            config: config.clone(),
+            wakeup: Arc::new(Notify::new()),
+        }
+    }
+
+    fn insert(&self, conn: &Connection, job: &Job) -> Result<(), Whatever> {
+        let task = serde_json::to_vec(&job.task).whatever_context("could not serialize task")?;
+        conn.execute(
+            "INSERT OR IGNORE INTO jobs (id, kind, study_instance_uid, encrypted_task, state, attempts, next_attempt_at,
+                 last_error, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                job.id,
+                job.task.kind(),
+                job.task.study_instance_uid(),
+                self.db.encrypt(&task),
+                job.state.as_str(),
+                job.attempts,
+                job.next_attempt_at as i64,
+                job.last_error,
+                job.created_at as i64,
+            ],
+        )
+        .with_whatever_context(|_| format!("could not save job {}", job.id))?;
+        Ok(())
+    }
+
+    /// Saves the state of `job`, its task is left as it is.
+    fn update_state(conn: &Connection, job: &Job) -> Result<(), Whatever> {
+        conn.execute(
+            "UPDATE jobs SET state = ?2, attempts = ?3, next_attempt_at = ?4, last_error = ?5 WHERE id = ?1",
+            params![job.id, job.state.as_str(), job.attempts, job.next_attempt_at as i64, job.last_error],
+        )
+        .with_whatever_context(|_| format!("could not save job {}", job.id))?;
+        Ok(())
+    }
+
+    /// Jobs matching `filter` in the order they were queued.
+    fn select(&self, conn: &Connection, filter: &str, params: impl Params) -> Result<Vec<Job>, Whatever> {
+        let mut statement = conn
+            .prepare(&format!("SELECT {} FROM jobs {} ORDER BY created_at, rowid", JOB_COLUMNS, filter))
+            .whatever_context("could not query jobs")?;
+        let rows = statement
+            .query_map(params, |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, Vec<u8>>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, u32>(3)?,
+                    row.get::<_, i64>(4)?,
+                    row.get::<_, Option<String>>(5)?,
+                    row.get::<_, i64>(6)?,
+                ))
+            })
+            .whatever_context("could not query jobs")?;
+        let mut jobs = vec![];
+        for row in rows {
+            let (id, encrypted_task, state, attempts, next_attempt_at, last_error, created_at) =
+                row.whatever_context("could not read job")?;
+            let task = self
+                .db
+                .decrypt(&encrypted_task)
+                .with_whatever_context(|_| format!("could not decrypt job {}", id))?;
+            jobs.push(Job {
+                task: serde_json::from_slice(&task).with_whatever_context(|_| format!("could not read task of job {}", id))?,
+                state: JobState::parse(&state)?,
+                attempts,
+                next_attempt_at: next_attempt_at as u64,
+                last_error,
+                created_at: created_at as u64,
+                id,
+            });
+        }
+        Ok(jobs)
+    }
+
+    fn get(&self, conn: &Connection, id: &str) -> Result<Option<Job>, Whatever> {
+        Ok(self.select(conn, "WHERE id = ?1", [id])?.pop())
+    }
+
+    /// Saves the progress a worker made on job `id`.
+    pub fn set_task(&self, id: &str, task: Task) -> Result<(), Whatever> {
+        let encrypted = self.db.encrypt(&serde_json::to_vec(&task).whatever_context("could not serialize task")?);
+        let updated = self.db.write(|tx| {
+            tx.execute(
+                "UPDATE jobs SET encrypted_task = ?2, study_instance_uid = ?3 WHERE id = ?1",
+                params![id, encrypted, task.study_instance_uid()],
+            )
+            .with_whatever_context(|_| format!("could not save job {}", id))
+        })?;
+        if updated == 0 {
+            whatever!("no job {}", id);
+        }
+        Ok(())
+    }
+
+    pub fn push(&self, task: Task) -> Result<Job, Whatever> {
+        let job = Job::new(task, unix_time());
+        self.db.write(|tx| self.insert(tx, &job))?;
+        self.wakeup.notify_one();
+        Ok(job)
+    }
+
+    /// All jobs in the order they were queued.
+    pub fn list(&self) -> Result<Vec<Job>, Whatever> {
+        self.db.read(|conn| self.select(conn, "", []))
+    }
+
+    /// Completes upload job `id` and adds `study`, the uploaded instance, to
+    /// the pending or waiting publication of its study, which is due
+    /// `quiet_period` from now. Both happen in one transaction, so a restart loses neither
+    /// the upload nor the announcement of the study.
+    pub fn complete_upload(&self, id: &str, study: Study, quiet_period: Duration) -> Result<(), Whatever> {
+        let uid = study.study_instance_uid.clone();
+        let due = (unix_time() + quiet_period.as_secs()) as i64;
+        self.db.write(|tx| {
+            tx.execute("DELETE FROM jobs WHERE id = ?1", [id])
+                .with_whatever_context(|_| format!("could not delete job {}", id))?;
+            let pending = self
+                .select(tx, "WHERE kind = 'publish' AND study_instance_uid = ?1 AND state IN ('pending', 'waiting')", [&uid])?
+                .pop();
+            let Some(mut job) = pending else {
+                return self.insert(tx, &Job::new(Task::Publish(study), due as u64));
+            };
+            if let Task::Publish(publication) = &mut job.task {
+                publication.merge(study);
+            }
+            let task = serde_json::to_vec(&job.task).whatever_context("could not serialize task")?;
+            tx.execute(
+                "UPDATE jobs SET encrypted_task = ?2, state = 'pending', next_attempt_at = ?3 WHERE id = ?1",
+                params![job.id, self.db.encrypt(&task), due],
+            )
+            .with_whatever_context(|_| format!("could not save job {}", job.id))?;
+            Ok(())
+        })
+    }
+
+    /// Completes publication job `id` and queues the announcement of
+    /// `study` in the same transaction. While uploads of the study are still
+    /// pending or running the publication waits for them instead, it is
+    /// due again once the last one completed, was given up or cancelled.
+    /// Returns whether the announcement was queued.
+    pub fn complete_publication(&self, id: &str, study: Study) -> Result<bool, Whatever> {
+        let queued = self.db.write(|tx| {
+            if Self::outstanding_uploads(tx, &study.study_instance_uid)? > 0 {
+                let task = serde_json::to_vec(&Task::Publish(study)).whatever_context("could not serialize task")?;
+                tx.execute(
+                    "UPDATE jobs SET encrypted_task = ?2, state = 'waiting' WHERE id = ?1",
+                    params![id, self.db.encrypt(&task)],
+                )
+                .with_whatever_context(|_| format!("could not save job {}", id))?;
+                return Ok(false);
+            }
+            tx.execute("DELETE FROM jobs WHERE id = ?1", [id])
+                .with_whatever_context(|_| format!("could not delete job {}", id))?;
+            self.insert(tx, &Job::new(Task::Notify(study), unix_time()))?;
+            Ok(true)
+        })?;
+        if queued {
+            self.wakeup.notify_one();
+        }
+        Ok(queued)
+    }
+
+    /// Uploads of the study which are pending or running.
+    fn outstanding_uploads(conn: &Connection, study_instance_uid: &str) -> Result<i64, Whatever> {
+        conn.query_row(
+            "SELECT count(*) FROM jobs WHERE kind = 'upload' AND study_instance_uid = ?1 AND state IN ('pending', 'running')",
+            [study_instance_uid],
+            |row| row.get(0),
+        )
+        .whatever_context("could not count queued uploads")
+    }
+
+    /// Makes the publication waiting for the uploads of the study of `task`
+    /// due, once none of them is pending or running any more. Returns
+    /// whether it was released.
+    fn release_publication(conn: &Connection, task: &Task) -> Result<bool, Whatever> {
+        let (Task::Upload(_), Some(uid)) = (task, task.study_instance_uid()) else {
+            return Ok(false);
+        };
+        if Self::outstanding_uploads(conn, uid)? > 0 {
+            return Ok(false);
+        }
+        let released = conn
+            .execute(
+                "UPDATE jobs SET state = 'pending', next_attempt_at = ?2
+                 WHERE kind = 'publish' AND study_instance_uid = ?1 AND state = 'waiting'",
+                params![uid, unix_time() as i64],
+            )
+            .with_whatever_context(|_| format!("could not release publication of study {}", uid))?;
+        Ok(released > 0)
+    }
+
+    /// Marks at most `limit` of the due pending jobs of `kind` as running
+    /// and returns them, the longest queued first.
+    fn claim_due(&self, kind: &str, limit: usize) -> Result<Vec<Job>, Whatever> {
+        let now = unix_time() as i64;
+        let limit = limit.min(i64::MAX as usize) as i64;
+        self.db.write(|tx| {
+            let mut due = self.select(
+                tx,
+                "WHERE id IN (SELECT id FROM jobs WHERE kind = ?1 AND state = 'pending' AND next_attempt_at <= ?2
+                     ORDER BY created_at, rowid LIMIT ?3)",
+                params![kind, now, limit],
+            )?;
+            for job in &mut due {
+                job.state = JobState::Running;
+                Self::update_state(tx, job)?;
+            }
+            Ok(due)
+        })
+    }
+
+    /// Makes jobs which were running when the gateway stopped pending again.
+    pub fn recover(&self) -> Result<usize, Whatever> {
+        self.db.write(|tx| {
+            tx.execute("UPDATE jobs SET state = 'pending' WHERE state = 'running'", [])
+                .whatever_context("could not resume running jobs")
+        })
+    }
+
+    pub fn complete(&self, id: &str) -> Result<(), Whatever> {
+        self.db.write(|tx| {
+            tx.execute("DELETE FROM jobs WHERE id = ?1", [id])
+                .with_whatever_context(|_| format!("could not delete job {}", id))?;
+            Ok(())
+        })
+    }
+
+    /// Records a failed attempt and schedules the next one with exponential
+    /// backoff, or dead-letters the job after `max_attempts` failures.
+    /// Jobs cancelled in the meantime stay cancelled.
+    pub fn fail(&self, id: &str, error: &str) -> Result<Option<Job>, Whatever> {
+        let (job, released) = self.db.write(|tx| {
+            let Some(mut job) = self.get(tx, id)? else {
+                return Ok((None, false));
+            };
+            job.attempts += 1;
+            job.last_error = Some(error.to_string());
+            if job.attempts >= self.config.max_attempts {
+                job.state = JobState::Dead;
+            } else {
+                job.state = JobState::Pending;
+                job.next_attempt_at = unix_time() + self.backoff(job.attempts);
+            }
+            Self::update_state(tx, &job)?;
+            let released = job.state == JobState::Dead && Self::release_publication(tx, &job.task)?;
+            Ok((Some(job), released))
+        })?;
+        if released {
+            self.wakeup.notify_one();
+        }
+        Ok(job)
+    }
+
+    /// Removes a successful job or records the failed attempt, logging the
+    /// outcome. Returns whether the job was dead-lettered.
+    pub fn finish(&self, job: &Job, result: Result<(), String>) -> bool {
+        let recorded = match result {
+            Ok(()) => self.complete(&job.id).map(|()| false),
+            Err(e) => self.fail(&job.id, &e).map(|failed| match failed {
+                Some(failed) if failed.state == JobState::Dead => {
+                    error!("Giving up {} after {} attempts: {}", job.describe(), failed.attempts, e);
+                    true
+                }
+                Some(_) => {
+                    warn!("{} failed, retrying later: {}", job.describe(), e);
+                    false
+                }
+                None => {
+                    warn!("{} failed after it was cancelled: {}", job.describe(), e);
+                    false
+                }
+            }),
+        };
+        recorded.unwrap_or_else(|e| {
+            error!("Could not update job {}: {}", job.id, snafu::Report::from_error(e));
+            false
+        })
+    }
+
+    /// Seconds to wait after the given number of failed attempts.
+    fn backoff(&self, attempts: u32) -> u64 {
+        let factor = 1u64.checked_shl(attempts.saturating_sub(1)).unwrap_or(u64::MAX);
+        self.config
+            .initial_backoff_secs
+            .saturating_mul(factor)
+            .min(self.config.max_backoff_secs)
+    }
+
+    /// Schedules a pending or dead-lettered job for immediately.
+    pub fn retry(&self, id: &str) -> Result<Job, Whatever> {
+        let job = self.db.write(|tx| {
+            let mut job = self.get(tx, id)?.with_whatever_context(|| format!("no job {}", id))?;
+            if job.state == JobState::Dead {
+                job.attempts = 0;
+            }
+            if job.state != JobState::Running {
+                job.state = JobState::Pending;
+                job.next_attempt_at = unix_time();
+            }
+            Self::update_state(tx, &job)?;
+            Ok(job)
+        })?;
+        self.wakeup.notify_one();
+        Ok(job)
+    }
+
+    /// Removes a job. Files of cancelled uploads are kept in the run
+    /// directory, their study is published without them.
+    pub fn cancel(&self, id: &str) -> Result<Job, Whatever> {
+        let (job, released) = self.db.write(|tx| {
+            let job = self.get(tx, id)?.with_whatever_context(|| format!("no job {}", id))?;
+            tx.execute("DELETE FROM jobs WHERE id = ?1", [id])
+                .with_whatever_context(|_| format!("could not delete job {}", id))?;
+            let released = Self::release_publication(tx, &job.task)?;
+            Ok((job, released))
+        })?;
+        if released {
+            self.wakeup.notify_one();
+        }
+        Ok(job)
+    }
+
+    /// Waits until a job is queued or retried, at most [`POLL_INTERVAL`].
+    async fn wait(&self) {
+        tokio::select! {
+            _ = self.wakeup.notified() => {}
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+        }
+    }
+}
+
+/// Hands due jobs to the upload workers, the manifest publisher and the
+/// Matrix client. Each worker gets at most as many jobs as its channel has
+/// room for, the others stay pending in the queue, so a backlog of one kind
+/// of job does not hold up the others.
+pub async fn dispatch(queue: JobQueue, uploads: Sender<Job>, publications: Sender<Job>, notifications: Sender<Job>, imports: Sender<Job>) {
+    match queue.recover() {
+        Ok(0) => {}
+        Ok(recovered) => warn!("Resuming {} jobs interrupted by the last shutdown", recovered),
+        Err(e) => error!("Could not resume interrupted jobs: {}", snafu::Report::from_error(e)),
+    }
+    let workers = [("upload", &uploads), ("publish", &publications), ("notify", &notifications), ("import", &imports)];
+    loop {
+        for (kind, worker) in workers {
+            if worker.capacity() == 0 {
+                continue;
+            }
+            let due = match queue.claim_due(kind, worker.capacity()) {
+                Ok(due) => due,
+                Err(e) => {
+                    error!("Could not read job queue: {}", snafu::Report::from_error(e));
+                    continue;
+                }
+            };
+            for job in due {
+                // only sent from here, the channel has room for every claimed job
+                if let Err(TrySendError::Full(job) | TrySendError::Closed(job)) = worker.try_send(job) {
+                    // the job stays running and is resumed after a restart
+                    error!("No worker for {}", job.describe());
+                }
+            }
+        }
+        queue.wait().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::matrix::client::ImportStage;
+
+    fn config() -> JobsConfig {
+        JobsConfig { max_attempts: 3, initial_backoff_secs: 30, max_backoff_secs: 100 }
+    }
+
+    fn import(study_id: &str) -> Task {
+        Task::Import(Import {
+            room_id: "!room:example.org".to_string(),
+            study_id: study_id.to_string(),
+            hex_key: "aa".to_string(),
+            stage: ImportStage::Received,
+        })
+    }
+
+    fn claim(queue: &JobQueue) -> Vec<Job> {
+        ["upload", "publish", "notify", "import"]
+            .into_iter()
+            .flat_map(|kind| queue.claim_due(kind, usize::MAX).unwrap())
+            .collect()
+    }
+
+    fn open(dir: &Path) -> JobQueue {
+        JobQueue::open(StudyDb::open(dir, "secret").unwrap(), &config())
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_maximum() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = open(dir.path());
+        let delays: Vec<u64> = [1, 2, 3, 4, 64, u32::MAX].into_iter().map(|attempts| queue.backoff(attempts)).collect();
+        assert_eq!(delays, [30, 60, 100, 100, 100, 100]);
+    }
+
+    #[test]
+    fn claims_due_jobs_per_kind_up_to_the_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = open(dir.path());
+        let first = queue.push(import("1.2.3")).unwrap();
+        queue.push(import("1.2.4")).unwrap();
+        queue.push(upload("1.2.3.4.1")).unwrap();
+        let claimed = queue.claim_due("import", 1).unwrap();
+        assert_eq!(claimed.iter().map(|job| job.id.as_str()).collect::<Vec<_>>(), [first.id.as_str()]);
+        assert_eq!(queue.claim_due("import", 5).unwrap().len(), 1);
+        assert!(queue.claim_due("import", 5).unwrap().is_empty());
+        assert_eq!(queue.claim_due("upload", 5).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn retries_failed_jobs_later_and_dead_letters_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = open(dir.path());
+        let job = queue.push(import("1.2.3")).unwrap();
+        assert_eq!(claim(&queue).len(), 1);
+        assert!(claim(&queue).is_empty());
+
+        let failed = queue.fail(&job.id, "unreachable").unwrap().unwrap();
+        assert_eq!((failed.state, failed.attempts), (JobState::Pending, 1));
+        assert!(failed.next_attempt_at >= unix_time() + 29);
+        assert!(claim(&queue).is_empty());
+
+        queue.fail(&job.id, "unreachable").unwrap();
+        let dead = queue.fail(&job.id, "still unreachable").unwrap().unwrap();
+        assert_eq!((dead.state, dead.attempts), (JobState::Dead, 3));
+        assert_eq!(dead.last_error.as_deref(), Some("still unreachable"));
+        assert!(claim(&queue).is_empty());
+
+        let retried = queue.retry(&job.id).unwrap();
+        assert_eq!((retried.state, retried.attempts), (JobState::Pending, 0));
+        let due = claim(&queue);
+        assert_eq!(due.len(), 1);
+        assert!(matches!(&due[0].task, Task::Import(i) if i.study_id == "1.2.3"));
+
+        queue.cancel(&job.id).unwrap();
+        assert_eq!(queue.fail(&job.id, "cancelled").unwrap().map(|job| job.id), None);
+        assert!(queue.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn finishing_tells_whether_the_job_was_given_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = open(dir.path());
+        let job = queue.push(import("1.2.3")).unwrap();
+        assert!(!queue.finish(&job, Err("unreachable".to_string())));
+        assert!(!queue.finish(&job, Err("unreachable".to_string())));
+        assert!(queue.finish(&job, Err("unreachable".to_string())));
+        assert!(!queue.finish(&job, Ok(())));
+        assert!(queue.list().unwrap().is_empty());
+    }
+
+    fn upload(sop_instance_uid: &str) -> Task {
+        Task::Upload(StoredInstance {
+            study_instance_uid: "1.2.3".to_string(),
+            sop_class_uid: "1.2.840.10008.5.1.4.1.1.2".to_string(),
+            sop_instance_uid: sop_instance_uid.to_string(),
+            series_instance_uid: "1.2.3.4".to_string(),
+            transfer_syntax_uid: "1.2.840.10008.1.2.1".to_string(),
+            calling_ae_title: "CT01".to_string(),
+            file: sop_instance_uid.into(),
+        })
+    }
+
+    fn study() -> Study {
+        Study {
+            study_instance_uid: "1.2.3".to_string(),
+            study_instance_uid_hash: "hash".to_string(),
+            study_date: "20240101".to_string(),
+            patient_id: "P1".to_string(),
+            patient_name: "Doe^Jane".to_string(),
+            patient_birth_date: "19700101".to_string(),
+            new_study: true,
+            hex_key: "aa".to_string(),
+            series_instance_uids: ["1.2.3.4".to_string()].into(),
+            instance_count: 1,
+            instances: vec![],
+            failed_instances: vec![],
+        }
+    }
+
+    fn kinds(queue: &JobQueue) -> Vec<&'static str> {
+        queue.list().unwrap().iter().map(|job| job.task.kind()).collect()
+    }
+
+    #[test]
+    fn announces_studies_once_their_uploads_completed() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = open(dir.path());
+        let first = queue.push(upload("1.2.3.4.1")).unwrap();
+        let second = queue.push(upload("1.2.3.4.2")).unwrap();
+        claim(&queue);
+
+        queue.complete_upload(&first.id, study(), Duration::from_secs(60)).unwrap();
+        queue.complete_upload(&second.id, study(), Duration::from_secs(60)).unwrap();
+        let jobs = queue.list().unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert!(jobs[0].next_attempt_at >= unix_time() + 59);
+        assert!(matches!(&jobs[0].task, Task::Publish(study) if study.instance_count == 2));
+        assert!(claim(&queue).is_empty());
+
+        let publication = queue.retry(&jobs[0].id).unwrap();
+        claim(&queue);
+        let third = queue.push(upload("1.2.3.4.3")).unwrap();
+        assert!(!queue.complete_publication(&publication.id, study()).unwrap());
+        assert_eq!(kinds(&queue), ["publish", "upload"]);
+        assert_eq!(queue.list().unwrap()[0].state, JobState::Waiting);
+
+        claim(&queue);
+        queue.complete_upload(&third.id, study(), Duration::ZERO).unwrap();
+        let publication = claim(&queue).pop().unwrap();
+        assert!(queue.complete_publication(&publication.id, study()).unwrap());
+        assert_eq!(kinds(&queue), ["notify"]);
+    }
+
+    #[test]
+    fn announces_studies_without_uploads_given_up_or_cancelled() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = open(dir.path());
+        let uploaded = queue.push(upload("1.2.3.4.1")).unwrap();
+        let dead = queue.push(upload("1.2.3.4.2")).unwrap();
+        let cancelled = queue.push(upload("1.2.3.4.3")).unwrap();
+        claim(&queue);
+        queue.complete_upload(&uploaded.id, study(), Duration::ZERO).unwrap();
+        queue.fail(&dead.id, "unreachable").unwrap();
+        let publication = claim(&queue).pop().unwrap();
+        assert!(!queue.complete_publication(&publication.id, study()).unwrap());
+
+        for _ in 1..config().max_attempts {
+            queue.fail(&dead.id, "unreachable").unwrap();
+        }
+        assert!(claim(&queue).is_empty());
+        queue.cancel(&cancelled.id).unwrap();
+        let publication = claim(&queue).pop().unwrap();
+        assert!(queue.complete_publication(&publication.id, study()).unwrap());
+        assert_eq!(kinds(&queue), ["upload", "notify"]);
+        assert_eq!(queue.list().unwrap()[0].state, JobState::Dead);
+    }
+
+    #[test]
+    fn resumes_running_jobs_after_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = open(dir.path());
+        let first = queue.push(import("1.2.3")).unwrap();
+        claim(&queue);
+        queue.push(import("1.2.4")).unwrap();
+        drop(queue);
+
+        let queue = open(dir.path());
+        assert_eq!(queue.recover().unwrap(), 1);
+        let jobs = queue.list().unwrap();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[0].id, first.id);
+        assert!(jobs.iter().all(|job| job.state == JobState::Pending));
+    }
+}
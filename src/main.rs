@@ -1,111 +1,324 @@
+mod config;
+mod jobs;
 mod matrix;
+mod secrets;
+mod storage;
 mod studies;
 mod dcm;
 mod ui;
 use dcm::storescp::receive;
-use dicom_core::chrono::Duration;
-use studies::store::{StudyStore, Study};
+use dcm::storescu::echo;
+use dcm::access::AccessPolicy;
+use dcm::tls;
+use openssl::ssl::SslAcceptor;
+use config::GatewayConfig;
+use jobs::queue::{dispatch, Job, JobQueue, Task};
+use secrets::{KeystoreSecrets, ProviderKind};
+use storage::object_store::ObjectStore;
+use studies::db::StudyDb;
+use studies::store::StudyStore;
 use matrix::client::process_messages;
-use tokio::time::sleep;
 use tokio::task;
-use std::thread;
 use std::sync::Arc;
-use tokio::sync::mpsc;
-
-use std::net::{Ipv4Addr, SocketAddrV4, TcpListener, TcpStream};
-use std::path::{Path, PathBuf};
-use clap::Parser;
-use tracing::{error, info, Level};
-use dicom_dictionary_std::tags;
-use dicom_object::open_file;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, Mutex, Semaphore};
+use tokio::sync::mpsc::Receiver;
+
+use std::net::{Ipv4Addr, SocketAddrV4, TcpStream};
+use std::path::PathBuf;
+use tracing::{error, info, warn, Level};
 use iced::{Application, Settings};
-use microkv::MicroKV;
-use snafu::{ResultExt, Whatever};
-use url::Url;
+use snafu::{ensure_whatever, Whatever};
 use structopt::StructOpt;
-use tokio::sync::mpsc::Sender;
 use ui::activity::SetupUI;
 
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "basic")]
 struct Args{
-    #[structopt(long = "ae_title", default_value = "DCMSHARE")]
-    ae_title: String,
-    #[structopt(long = "run_dir", default_value = ".dcmshare")]
-    run_dir: PathBuf,
-    #[structopt(long = "port", default_value = "11111")]
-    port: u16,
-    #[structopt(long = "db_dir", default_value = "")]
-    db_dir: PathBuf,
-    #[structopt(long = "db_password", default_value = "correcthorsestablebattery")]
-    db_password: String,
-    #[structopt(long = "matrix_user", default_value = "@joker:serverella")]
-    user: String,
-    #[structopt(long = "matrix_password", default_value = "joker")]
-    user_password: String,
+    #[structopt(long = "config", default_value = "dcmshare.toml")]
+    config: PathBuf,
+    #[structopt(long = "profile")]
+    profile: Option<String>,
+    #[structopt(long = "ae_title")]
+    ae_title: Option<String>,
+    #[structopt(long = "run_dir")]
+    run_dir: Option<PathBuf>,
+    #[structopt(long = "port")]
+    port: Option<u16>,
+    #[structopt(long = "db_dir")]
+    db_dir: Option<PathBuf>,
+    #[structopt(long = "matrix_homeserver")]
+    homeserver: Option<String>,
+    #[structopt(long = "matrix_user")]
+    user: Option<String>,
+    #[structopt(long = "s3_endpoint")]
+    s3_endpoint: Option<String>,
+    #[structopt(long = "s3_bucket")]
+    s3_bucket: Option<String>,
+    /// Read the value of the named secret from stdin, store it in the
+    /// keystore and exit.
+    #[structopt(long = "store_secret")]
+    store_secret: Option<String>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Verify the connection to a PACS destination with a C-ECHO and exit
+    Echo {
+        /// Name of the destination, the default destination if omitted
+        destination: Option<String>,
+    },
+    /// Manage the queued upload, notification and import jobs and exit
+    Jobs(JobsCommand),
+    /// Query the history of shared studies and exit
+    Studies(StudiesCommand),
+}
+
+#[derive(StructOpt, Debug)]
+enum StudiesCommand {
+    /// List the most recently shared studies
+    List {
+        #[structopt(long, default_value = "50")]
+        limit: usize,
+    },
+    /// Tell whether a study was already shared with a recipient
+    SharedWith {
+        study_instance_uid: String,
+        /// Matrix room ID
+        recipient: String,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum JobsCommand {
+    /// List queued and dead-lettered jobs
+    List,
+    /// Run a pending or dead-lettered job again now
+    Retry { id: String },
+    /// Remove a job from the queue
+    Cancel { id: String },
 }
 
-// impl Clone for Args {
-//     fn clone(&self) -> Self {
-//         Args {
-//             ae_title: self.ae_title.clone(),
-//             run_dir: self.run_dir.clone(),
-//             db_dir: self.db_dir.clone(),
-//             port: self.port,
-//             db_password: self.db_password.clone(),
-//             user: self.user.clone(),
-//             user_password: self.user_password.clone(),
-//         }
-//     }
-// }
-
-async fn handle_connection(scu_stream: TcpStream, tx: Sender<Study>) {
-    let study_store = StudyStore {
-        run_dir: PathBuf::from(".dcmshare"),
-        db: MicroKV::open_with_base_path("studies", PathBuf::from(""))
-            .expect("Failed to create MicroKV from a stored file or create MicroKV for this file")
-            .set_auto_commit(true)
-            .with_pwd_clear("correcthorsestaplebattery".to_string())
-    };
-    match receive(scu_stream, "DCMSHARE".to_string(), PathBuf::from(".dcmshare")) {
-        Ok(sop_instance_uid) => {
-            println!("receiving study");
-            let study = study_store.add_series(&sop_instance_uid).await.unwrap();
-            let res = tx.send(study).await;
-            match res {
-                Err(E) => println!("{}",E.to_string()),
-                Ok(T) => println!("sent")
+impl Args {
+    /// Loads the configuration file and lets options given on the command
+    /// line take precedence over it.
+    fn config(&self) -> Result<GatewayConfig, Whatever> {
+        let mut config = GatewayConfig::load(&self.config, self.profile.as_deref())?;
+        if let Some(v) = &self.ae_title { config.dicom.ae_title = v.clone(); }
+        if let Some(v) = &self.run_dir { config.dicom.run_dir = v.clone(); }
+        if let Some(v) = self.port { config.dicom.port = v; }
+        if let Some(v) = &self.db_dir { config.db.dir = v.clone(); }
+        if let Some(v) = &self.homeserver { config.matrix.homeserver = v.clone(); }
+        if let Some(v) = &self.user { config.matrix.user = v.clone(); }
+        if let Some(v) = &self.s3_endpoint { config.s3.endpoint = v.clone(); }
+        if let Some(v) = &self.s3_bucket { config.s3.bucket = v.clone(); }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// Receives the instances of one association and queues them for upload.
+async fn handle_connection(scu_stream: TcpStream, ae_title: String, run_dir: PathBuf, access: Arc<AccessPolicy>, tls: Option<SslAcceptor>, jobs: JobQueue) {
+    let peer_addr = scu_stream.peer_addr().ok();
+    let peer = peer_addr.map(|a| a.to_string()).unwrap_or_default();
+    // Whatever is not Send, report the error while still on the blocking thread
+    let received = task::spawn_blocking(move || {
+        let scu_stream = match &tls {
+            Some(acceptor) => tls::accept(acceptor, scu_stream),
+            None => Ok(scu_stream),
+        };
+        scu_stream
+            .and_then(|scu_stream| receive(scu_stream, peer_addr.map(|a| a.ip()), ae_title, run_dir, &access))
+            .map_err(|e| snafu::Report::from_error(e).to_string())
+    });
+    match received.await {
+        Ok(Ok(instances)) => {
+            info!("Received {} instances from {}", instances.len(), peer);
+            for instance in instances {
+                if let Err(e) = jobs.push(Task::Upload(instance)) {
+                    error!("Could not queue instance for upload: {}", snafu::Report::from_error(e));
+                }
             }
         },
-        Err(E) => error!("Something went wrong"),
+        Ok(Err(e)) => error!("Association with {} failed: {}", peer, e),
+        Err(e) => error!("Association with {} aborted: {}", peer, e),
+    }
+}
+
+/// Runs the queued upload jobs. Several workers share one queue.
+///
+/// A successful upload completes its job together with queueing the
+/// publication of the study, which is postponed by `quiet_period` with
+/// every further instance, so a study received over several associations
+/// is announced once.
+async fn upload_worker(queue: Arc<Mutex<Receiver<Job>>>, jobs: JobQueue, study_store: StudyStore, quiet_period: Duration) {
+    loop {
+        let Some(job) = queue.lock().await.recv().await else { break };
+        let Task::Upload(instance) = &job.task else { continue };
+        // Whatever is not Send, report it before the next await
+        let added = study_store.add_series(instance).await.map_err(|e| snafu::Report::from_error(e).to_string());
+        match added {
+            Ok(study) if study.failed_instances.is_empty() => {
+                if let Err(e) = jobs.complete_upload(&job.id, study, quiet_period) {
+                    error!("Could not update job {}: {}", job.id, snafu::Report::from_error(e));
+                }
+            }
+            Ok(_) => {
+                jobs.finish(&job, Err("upload failed".to_string()));
+            }
+            Err(e) => {
+                jobs.finish(&job, Err(e));
+            }
+        }
+    }
+}
+
+/// Runs the due publication jobs: uploads the manifest of the study and
+/// queues its announcement. Studies whose manifest could not be uploaded
+/// are not announced, as the recipients could not verify them, nor are
+/// studies with uploads still pending or running.
+async fn publish_manifests(mut rx: Receiver<Job>, study_store: StudyStore, gateway: String, jobs: JobQueue) {
+    while let Some(job) = rx.recv().await {
+        let Task::Publish(study) = &job.task else { continue };
+        let mut study = study.clone();
+        let published = study_store
+            .publish_manifest(&mut study, &gateway)
+            .await
+            .map_err(|e| format!("Could not upload manifest: {}", snafu::Report::from_error(e)));
+        if let Err(e) = published {
+            jobs.finish(&job, Err(e));
+            continue;
+        }
+        let uid = study.study_instance_uid.clone();
+        let (series, instances) = (study.series_count(), study.instance_count);
+        match jobs.complete_publication(&job.id, study) {
+            Ok(true) => info!("Study {} complete with {} series, {} instances", uid, series, instances),
+            // the uploaded instances are in the manifest, the study is
+            // published again once the queued uploads are done
+            Ok(false) => warn!("Study {} not announced yet, waiting for its queued uploads", uid),
+            Err(e) => error!("Could not update job {}: {}", job.id, snafu::Report::from_error(e)),
+        }
     }
 }
 
-async fn listen(tx: Sender<Study>) {
-    let ae_title = String::from("DCMSHARE");
-    let listen_addr = SocketAddrV4::new(Ipv4Addr::from(0), 11111);
-    let listener = TcpListener::bind(listen_addr).unwrap();
+/// Accepts associations concurrently, at most `max_associations` at a time.
+async fn listen(listener: TcpListener, ae_title: String, run_dir: PathBuf, access: AccessPolicy, tls: Option<SslAcceptor>, max_associations: usize, jobs: JobQueue) {
     info!(
         "{} listening on: tcp://{}",
-        ae_title, listen_addr
+        ae_title, listener.local_addr().unwrap()
     );
-    for stream in listener.incoming() {
-        println!("Incoming");
-        handle_connection(stream.unwrap(), tx.clone()).await;
+    if access.is_open() {
+        warn!("No allowed peers configured, accepting associations from any AE title");
+    }
+    let access = Arc::new(access);
+    let associations = Arc::new(Semaphore::new(max_associations));
+    loop {
+        let permit = associations.clone().acquire_owned().await.unwrap();
+        let scu_stream = match listener.accept().await.and_then(|(stream, _)| stream.into_std()) {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Could not accept connection: {}", e);
+                continue;
+            }
+        };
+        // dicom-ul works on blocking sockets
+        if let Err(e) = scu_stream.set_nonblocking(false) {
+            error!("Could not configure connection: {}", e);
+            continue;
+        }
+        let (ae_title, run_dir, access, tls, jobs) = (ae_title.clone(), run_dir.clone(), access.clone(), tls.clone(), jobs.clone());
+        tokio::spawn(async move {
+            handle_connection(scu_stream, ae_title, run_dir, access, tls, jobs).await;
+            drop(permit);
+        });
+    }
+}
+
+/// Fetches the database and Matrix passwords from the configured secret
+/// provider and opens the object store with the credentials it needs.
+fn credentials(config: &GatewayConfig) -> Result<(String, String, Arc<dyn ObjectStore>), Whatever> {
+    let secrets = config.secrets.provider()?;
+    Ok((
+        secrets.require(secrets::DB_PASSWORD)?,
+        secrets.require(secrets::MATRIX_PASSWORD)?,
+        config.storage.open(&config.s3, &*secrets)?,
+    ))
+}
+
+/// Runs a `jobs` command against the job queue, which may be in use by a
+/// running gateway.
+fn manage_jobs(config: &GatewayConfig, command: &JobsCommand) -> Result<(), Whatever> {
+    let db_password = config.secrets.provider()?.require(secrets::DB_PASSWORD)?;
+    let db = StudyDb::open(&config.db.dir, &db_password)?;
+    let jobs = JobQueue::open(db, &config.jobs);
+    match command {
+        JobsCommand::List => {
+            for job in jobs.list()? {
+                println!(
+                    "{}  {:?}  attempts: {}  {}{}",
+                    job.id, job.state, job.attempts, job.describe(),
+                    job.last_error.map(|e| format!("  last error: {}", e)).unwrap_or_default()
+                );
+            }
+        }
+        JobsCommand::Retry { id } => {
+            let job = jobs.retry(id)?;
+            println!("Retrying {}", job.describe());
+        }
+        JobsCommand::Cancel { id } => {
+            let job = jobs.cancel(id)?;
+            println!("Cancelled {}", job.describe());
+        }
     }
+    Ok(())
+}
+
+/// Runs a `studies` command against the study database.
+fn query_studies(config: &GatewayConfig, command: &StudiesCommand) -> Result<(), Whatever> {
+    let db_password = config.secrets.provider()?.require(secrets::DB_PASSWORD)?;
+    let db = StudyDb::open(&config.db.dir, &db_password)?;
+    match command {
+        StudiesCommand::List { limit } => {
+            for study in db.history(*limit)? {
+                println!(
+                    "{}  {}  {} ({})  uploaded: {}  failed: {}  shared with: {}",
+                    study.study_instance_uid, study.study_date, study.patient_name, study.patient_id,
+                    study.uploaded_instances, study.failed_instances,
+                    if study.recipients.is_empty() { "-".to_string() } else { study.recipients.join(", ") }
+                );
+            }
+        }
+        StudiesCommand::SharedWith { study_instance_uid, recipient } => {
+            match db.shared_with(study_instance_uid, recipient)? {
+                Some(instances) => println!("Shared with {} ({} instances)", recipient, instances),
+                None => println!("Not shared with {}", recipient),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads a secret value from stdin and stores it in the configured keystore.
+fn store_secret(config: &GatewayConfig, secret: &str) -> Result<(), Whatever> {
+    ensure_whatever!(config.secrets.provider == ProviderKind::Keystore,
+        "secrets can only be stored when the keystore secret provider is configured");
+    let keystore = KeystoreSecrets::unlock(&config.secrets.path, &secrets::keystore_passphrase()?)?;
+    eprintln!("Value of {}:", secret);
+    keystore.store(secret, &secrets::read_line()?)
 }
 
 #[tokio::main]
 async fn main() {
 
-    let (tx, mut rx) = mpsc::channel::<Study>(100);
+    let (publish_tx, publish_rx) = mpsc::channel::<Job>(100);
+    let (notify_tx, notify_rx) = mpsc::channel::<Job>(100);
+    let (import_tx, import_rx) = mpsc::channel::<Job>(100);
     let args = Args::from_args();
 
-    let mut settings = Settings::default();
-    settings.window.size = (500,700);
-    SetupUI::run(settings);
-
     tracing::subscriber::set_global_default(
         tracing_subscriber::FmtSubscriber::builder()
             .with_max_level(Level::INFO)
@@ -118,12 +331,101 @@ async fn main() {
             );
         });
 
-    std::fs::create_dir_all(PathBuf::from(".dcmshare")).unwrap_or_else(|e| {
+    let config = args.config().unwrap_or_else(|e| {
+        error!("Invalid configuration: {}", snafu::Report::from_error(e));
+        std::process::exit(-1);
+    });
+
+    if let Some(Command::Echo { destination }) = &args.command {
+        let name = destination.as_deref().unwrap_or(&config.pacs.default);
+        let Some(destination) = config.pacs.destination(name) else {
+            error!("Unknown PACS destination {:?}", name);
+            std::process::exit(-1);
+        };
+        if let Err(e) = echo(destination) {
+            error!("{}", snafu::Report::from_error(e));
+            std::process::exit(-2);
+        }
+        return;
+    }
+
+    if let Some(Command::Jobs(command)) = &args.command {
+        if let Err(e) = manage_jobs(&config, command) {
+            error!("{}", snafu::Report::from_error(e));
+            std::process::exit(-1);
+        }
+        return;
+    }
+
+    if let Some(Command::Studies(command)) = &args.command {
+        if let Err(e) = query_studies(&config, command) {
+            error!("{}", snafu::Report::from_error(e));
+            std::process::exit(-1);
+        }
+        return;
+    }
+
+    if let Some(secret) = &args.store_secret {
+        store_secret(&config, secret).unwrap_or_else(|e| {
+            error!("Could not store secret: {}", snafu::Report::from_error(e));
+            std::process::exit(-1);
+        });
+        return;
+    }
+
+    let (db_password, matrix_password, object_store) = credentials(&config).unwrap_or_else(|e| {
+        error!("Could not load credentials: {}", snafu::Report::from_error(e));
+        std::process::exit(-1);
+    });
+
+    let mut settings = Settings::default();
+    settings.window.size = (500,700);
+    SetupUI::run(settings);
+
+    std::fs::create_dir_all(&config.dicom.run_dir).unwrap_or_else(|e| {
         error!("Could not create output directory: {}", e);
         std::process::exit(-2);
     });
 
-    tokio::spawn( process_messages("https://nonelabs.com:4327","radioshare", "bHuj/?>.2334", rx) );
-    listen(tx).await;
+    let study_store = StudyStore::open(&config.dicom.run_dir, &config.db.dir, &db_password, object_store.clone(), config.retention.keep_received_files)
+        .unwrap_or_else(|e| {
+            error!("Could not open study database: {}", snafu::Report::from_error(e));
+            std::process::exit(-2);
+        });
+
+    let jobs = JobQueue::open(study_store.db.clone(), &config.jobs);
+
+    let listen_addr = SocketAddrV4::new(Ipv4Addr::from(0), config.dicom.port);
+    let listener = TcpListener::bind(listen_addr).await.unwrap_or_else(|e| {
+        error!("Could not listen on tcp://{}: {}", listen_addr, e);
+        std::process::exit(-2);
+    });
+
+    tokio::spawn( publish_manifests(publish_rx, study_store.clone(), config.dicom.ae_title.clone(), jobs.clone()) );
+    let matrix = tokio::spawn( process_messages(config.clone(), matrix_password, object_store, notify_rx, import_rx, jobs.clone(), study_store.db.clone()) );
+    // notifications and imports would stay running until a restart
+    tokio::spawn(async move {
+        match matrix.await {
+            Ok(Ok(())) => error!("Matrix client stopped"),
+            Ok(Err(e)) => error!("Matrix client failed: {e:#}"),
+            Err(e) => error!("Matrix client aborted: {}", e),
+        }
+        std::process::exit(-3);
+    });
+    let (queue_tx, queue_rx) = mpsc::channel::<Job>(config.dicom.upload_queue_size);
+    let queue_rx = Arc::new(Mutex::new(queue_rx));
+    for _ in 0..config.dicom.upload_workers {
+        tokio::spawn( upload_worker(queue_rx.clone(), jobs.clone(), study_store.clone(), Duration::from_secs(config.dicom.quiet_period_secs)) );
+    }
+    tokio::spawn( dispatch(jobs.clone(), queue_tx, publish_tx, notify_tx, import_tx) );
+    let access = AccessPolicy::new(&config.dicom.allowed_peers).unwrap_or_else(|e| {
+        error!("Invalid allowed peers: {}", snafu::Report::from_error(e));
+        std::process::exit(-1);
+    });
+    let tls = config.dicom.tls.acceptor().unwrap_or_else(|e| {
+        error!("Invalid TLS configuration: {}", snafu::Report::from_error(e));
+        std::process::exit(-1);
+    });
+    listen(listener, config.dicom.ae_title.clone(), config.dicom.run_dir.clone(), access, tls, config.dicom.max_associations, jobs).await;
 
 }
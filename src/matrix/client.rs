@@ -15,68 +15,143 @@
 
 use std::{env, fs, process::exit};
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use dcm::storescu::send;
+use std::sync::Arc;
+use dcm::storescu::{send_async, InstanceStatus, SendProgress};
 use anyhow::anyhow;
 use iced::futures::TryFutureExt;
 use iced::subscription::Recipe;
 use tokio::sync::mpsc::channel;
 
-use matrix_sdk::{config::SyncSettings, room::Room, ruma::events::room::{
+use matrix_sdk::{config::SyncSettings, event_handler::Ctx, room::{self, Room}, ruma::events::room::{
     member::StrippedRoomMemberEvent,
     message::{MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent},
 }, Client, LoopCtrl};
-use s3::{Bucket, Region};
-use s3::creds::Credentials;
+use matrix_sdk::ruma::RoomId;
+use serde_derive::{Deserialize, Serialize};
 use sodiumoxide::crypto::secretbox;
 use tokio::io::AsyncWriteExt;
 use tokio::sync::mpsc;
 use tokio::time::{sleep, Duration};
-use tracing::error;
+use tracing::{debug, error, info, warn};
+use snafu::{ensure_whatever, whatever, ResultExt, Whatever};
 use url::Url;
 use crate::dcm;
 
+use crate::config::{GatewayConfig, MatrixConfig, PacsConfig};
+use crate::jobs::queue::{Job, JobQueue, Task};
+use crate::matrix::share::{is_study_hash, key_line, parse_share};
+use crate::storage::object_store::{self, ObjectStore};
+use crate::studies::crypto::{decrypt_stream, parse_key};
+use crate::studies::manifest::{manifest_key, Digesting, Manifest, ManifestEntry};
+use crate::studies::db::StudyDb;
 use crate::studies::store::Study;
 
 pub async fn process_messages(
-    homeserver: &str,
-    matrix_username: &str,
-    matrix_password: &str,
-    mut rx: mpsc::Receiver<Study>
+    config: GatewayConfig,
+    matrix_password: String,
+    object_store: Arc<dyn ObjectStore>,
+    mut rx: mpsc::Receiver<Job>,
+    import_rx: mpsc::Receiver<Job>,
+    jobs: JobQueue,
+    db: StudyDb,
 ) -> anyhow::Result<()> {
-    println!("logged in as {matrix_username}");
+    let matrix_username = &config.matrix.user;
+    if config.matrix.gateways.is_empty() {
+        warn!("No gateways configured, shares posted to the rooms are not imported");
+    }
+    // notifications wait in the job queue while the homeserver is unreachable
+    let mut delay = 2;
+    let client = loop {
+        match login(&config, &matrix_password).await {
+            Ok(client) => break client,
+            Err(err) => {
+                error!("Could not log in to {} ({err:?}), retrying in {delay}s", config.matrix.homeserver);
+                sleep(Duration::from_secs(delay)).await;
+                delay = (delay * 2).min(3600);
+            }
+        }
+    };
+    info!("Logged in as {matrix_username}");
+
+    client.add_event_handler_context(config.pacs.clone());
+    client.add_event_handler_context(config.matrix.clone());
+    client.add_event_handler_context(jobs.clone());
+    client.add_event_handler(room_invitation);
+    // the joined rooms are known once synced, imports resumed from the
+    // queue report to them
+    let sync_token = client.sync_once(SyncSettings::default()).await?.next_batch;
+    client.add_event_handler(on_room_message);
+    let cc = client.clone();
+    let thread = tokio::spawn( async move {
+        let settings = SyncSettings::default().token(sync_token);
+        cc.sync(settings).await;
+    });
+    let import_dir = ImportDir {
+        path: config.dicom.run_dir.join("import"),
+        keep_files: config.retention.keep_downloaded_files,
+    };
+    tokio::spawn(run_imports(client.clone(), import_rx, jobs.clone(), object_store, config.pacs.clone(), import_dir));
+    while let Some(job) = rx.recv().await {
+        let Task::Notify(study) = &job.task else { continue };
+        let result = announce(&client, &config, &db, study).await.map_err(|e| format!("{e:#}"));
+        jobs.finish(&job, result);
+    }
+    thread.await;
+    Ok(())
+}
+
+async fn login(config: &GatewayConfig, matrix_password: &str) -> anyhow::Result<Client> {
     let client = Client::builder()
-        .homeserver_url(homeserver)
+        .homeserver_url(&config.matrix.homeserver)
         .handle_refresh_tokens()
         .build()
         .await?;
-    let response = client
-        .login_username(matrix_username, matrix_password)
+    client
+        .login_username(&config.matrix.user, matrix_password)
         .initial_device_display_name("dcmshare client")
         .request_refresh_token()
         .send()
         .await?;
+    Ok(client)
+}
 
-    let cc = client.clone();
-    let thread = tokio::spawn( async move {
-        cc.add_event_handler(room_invitation);
-        let sync_token = cc.sync_once(SyncSettings::default()).await.unwrap().next_batch;
-        cc.add_event_handler(on_room_message);
-        let settings = SyncSettings::default().token(sync_token);
-        cc.sync(settings).await;
-    });
-    while let Some(study) = rx.recv().await {
-        let sync_token = client.sync_once(SyncSettings::default()).await.unwrap().next_batch;
-        let invited_rooms = client.joined_rooms();
-        let content = RoomMessageEventContent::text_plain(format!("PatientenID:{}\nName:{}\nGeburtstag:{}\nKey:{}/{}",&study.patient_id,&study.patient_name,&study.patient_birth_date,&study.study_instance_uid_hash,&study.hex_key));
-        for joined_id in invited_rooms {
-            let room = client.get_joined_room(&joined_id.room_id()).unwrap();
-            room.send(content.clone(), None).await.unwrap();
+/// Posts the key of `study` in the configured rooms and records to which
+/// rooms it was posted. Rooms which already received the study with as many
+/// instances are skipped, so a retried notification is not posted twice.
+async fn announce(client: &Client, config: &GatewayConfig, db: &StudyDb, study: &Study) -> anyhow::Result<()> {
+    // Whatever is not Send, keep only the message
+    let report = |e: Whatever| anyhow!(snafu::Report::from_error(e).to_string());
+    client.sync_once(SyncSettings::default()).await?;
+    let content = RoomMessageEventContent::text_plain(format!("PatientenID:{}\nName:{}\nGeburtstag:{}\nSerien:{}\nBilder:{}\n{}",&study.patient_id,&study.patient_name,&study.patient_birth_date,study.series_count(),study.instance_count,key_line(&study.study_instance_uid_hash,&study.hex_key)));
+    let mut rooms = 0;
+    let mut notified = vec![];
+    let mut sent = Ok(());
+    for room in client.joined_rooms() {
+        if !config.matrix.rooms.is_empty() && !config.matrix.rooms.iter().any(|r| r.as_str() == room.room_id().as_str()) {
+            continue;
+        }
+        rooms += 1;
+        let room_id = room.room_id().to_string();
+        let shared = db.shared_with(&study.study_instance_uid, &room_id).map_err(report)?;
+        if shared.map_or(false, |instances| instances >= study.instance_count) {
+            continue;
+        }
+        if let Err(e) = room.send(content.clone(), None).await {
+            sent = Err(e);
+            break;
         }
+        notified.push(room_id);
+    }
+    if !notified.is_empty() {
+        db.record_share(study, &notified).map_err(report)?;
+    }
+    sent?;
+    if rooms == 0 {
+        return Err(anyhow!("no room to notify has been joined"));
     }
-    thread.await;
     Ok(())
 }
 
@@ -90,69 +165,403 @@ async fn room_invitation(
     }
     if let Room::Invited(room) = room {
         tokio::spawn(async move {
-            println!("Joining room {}", room.room_id());
+            info!("Joining room {}", room.room_id());
             let mut delay = 2;
             while let Err(err) = room.accept_invitation().await {
-                eprintln!("Failed to join room {} ({err:?}), retrying in {delay}s", room.room_id());
+                warn!("Failed to join room {} ({err:?}), retrying in {delay}s", room.room_id());
                 sleep(Duration::from_secs(delay)).await;
                 delay *= 2;
                 if delay > 3600 {
-                    eprintln!("Can't join room {} ({err:?})", room.room_id());
-                    break;
+                    error!("Can't join room {} ({err:?})", room.room_id());
+                    return;
                 }
             }
-            println!("Successfully joined room {}", room.room_id());
+            info!("Joined room {}", room.room_id());
         });
     }
 }
 
-async fn on_room_message(event: OriginalSyncRoomMessageEvent, room: Room) {
-    println!("New room message");
+/// Queues the import of studies shared by the configured gateways.
+async fn on_room_message(
+    event: OriginalSyncRoomMessageEvent,
+    room: Room,
+    pacs: Ctx<PacsConfig>,
+    matrix: Ctx<MatrixConfig>,
+    jobs: Ctx<JobQueue>,
+) {
     let Room::Joined(room) = room else { return };
     let MessageType::Text(text_content) = event.content.msgtype else { return };
-    let sender = event.sender;
-    println!("{}",sender.to_string());
-    if !sender.to_string().contains("share") && text_content.body.contains("Key:") {
-        let start_pattern = "creds:";
-        let end_pattern = "/";
-        let start_index = text_content.body.find(start_pattern).unwrap() + start_pattern.len();
-        let end_index = text_content.body[start_index..].find(end_pattern).unwrap();
-        let study_id = &text_content.body[start_index..start_index+end_index];
-        let hex_key = &text_content.body[start_index+end_index + end_pattern.len()..];
-        let bucket = Bucket::new_with_path_style("studies",Region::Custom {region:"".to_owned(),endpoint: "https://nonelabs.com:9090".to_owned()}, Credentials{
-            access_key: Some("minio".to_owned()),
-            secret_key: Some("f381hf1h2g70hvq23ubgiu123r".to_owned()),
-            expiration: None,
-            security_token: None,
-            session_token: None,
-        }).unwrap();
-        let results = bucket.list(study_id.to_string(), None).await;
-        let mut dicom_files: Vec<PathBuf> = vec!();
-        match results {
-            Ok(res) => {
-                for r in res {
-                    for object in r.contents {
-                        let data = bucket.get_object(&object.key).await.unwrap();
-                        let file_name = format!("./{}.dat",&object.key);
-                        std::fs::create_dir_all(PathBuf::from(study_id)).unwrap_or_else(|e| {
-                            error!("Could not create output directory: {}", e);
-                            std::process::exit(-2);
-                        });
-                        let mut file = File::create(&file_name).unwrap();
-                        file.write_all(&data.bytes());
+    let sender = event.sender.as_str();
+    if sender == matrix.user {
+        return;
+    }
+    let share = match parse_share(&text_content.body) {
+        Ok(Some(share)) => share,
+        Ok(None) => return,
+        Err(e) => {
+            warn!("Ignoring malformed share from {} in room {}: {}", sender, room.room_id(), e);
+            return;
+        }
+    };
+    if !matrix.gateways.iter().any(|gateway| gateway == sender) {
+        warn!("Ignoring share from {} in room {}, it is not a configured gateway", sender, room.room_id());
+        return;
+    }
+    if pacs.destination_for_room(room.room_id().as_str()).is_none() {
+        error!("No PACS destination configured for room {}", room.room_id());
+        post(Some(&room), "No PACS destination configured for this room").await;
+        return;
+    }
+    let study_id = share.study_hash;
+    let import = Import {
+        room_id: room.room_id().to_string(),
+        study_id: study_id.clone(),
+        hex_key: share.hex_key,
+        stage: ImportStage::Received,
+    };
+    // the import can take a long time, it runs from the job queue so
+    // that it survives a restart
+    let queued = jobs.push(Task::Import(import)).map_err(|e| snafu::Report::from_error(e).to_string());
+    if let Err(e) = queued {
+        error!("Could not queue import of study {}: {}", study_id, e);
+        post(Some(&room), "Could not queue the study for import").await;
+    }
+}
+
+/// A study shared in a Matrix room, imported by a job which records the
+/// stage it reached.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Import {
+    pub room_id: String,
+    pub study_id: String,
+    pub hex_key: String,
+    pub stage: ImportStage,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ImportStage {
+    /// Not started, the manifest preview was not posted yet
+    Received,
+    /// Downloading, verifying and decrypting, instances already decrypted
+    /// are kept in the import directory
+    Downloading,
+    /// Sending the decrypted instances to the PACS, instances already stored
+    /// were deleted unless downloaded files are kept
+    Storing,
+}
+
+/// Directory downloaded studies are decrypted to before they are imported.
+#[derive(Clone)]
+struct ImportDir {
+    path: PathBuf,
+    keep_files: bool,
+}
+
+/// Runs the queued import jobs, each in its own task.
+async fn run_imports(
+    client: Client,
+    mut rx: mpsc::Receiver<Job>,
+    jobs: JobQueue,
+    object_store: Arc<dyn ObjectStore>,
+    pacs: PacsConfig,
+    import_dir: ImportDir,
+) {
+    while let Some(job) = rx.recv().await {
+        let Task::Import(import) = job.task.clone() else { continue };
+        let room = RoomId::parse(&import.room_id).ok().and_then(|id| client.get_joined_room(&id));
+        let (jobs, object_store, pacs, import_dir) = (jobs.clone(), object_store.clone(), pacs.clone(), import_dir.clone());
+        tokio::spawn(async move {
+            let result = import_study(room.as_ref(), &job.id, import, &jobs, object_store, &pacs, &import_dir).await;
+            let error = result.as_ref().err().cloned();
+            // failed attempts are retried quietly, the room only hears of
+            // imports which were given up
+            if let (true, Some(e)) = (jobs.finish(&job, result), error) {
+                post(room.as_ref(), &format!("{}, giving up the import (job {})", e, job.id)).await;
+            }
+        });
+    }
+}
 
+/// Posts a progress message, imports go on if it cannot be delivered.
+async fn post(room: Option<&room::Joined>, text: &str) {
+    let Some(room) = room else { return };
+    let content = RoomMessageEventContent::text_plain(text);
+    if let Err(e) = room.send(content, None).await {
+        warn!("Could not post to room {}: {}", room.room_id(), e);
+    }
+}
+
+/// Downloads and decrypts a study and stores it in the PACS destination of
+/// its room, reporting the progress to `room`. The decrypted files are
+/// deleted once they were stored.
+///
+/// Continues from the stage saved in job `job_id`. Errors are worth
+/// retrying, invalid shares are reported and completed.
+async fn import_study(
+    room: Option<&room::Joined>,
+    job_id: &str,
+    mut import: Import,
+    jobs: &JobQueue,
+    object_store: Arc<dyn ObjectStore>,
+    pacs: &PacsConfig,
+    import_dir: &ImportDir,
+) -> Result<(), String> {
+    let _ = sodiumoxide::init();
+    let study_id = import.study_id.clone();
+    if !is_study_hash(&study_id) {
+        error!("Invalid study hash {:?} in import job {}", study_id, job_id);
+        post(room, "Invalid share, the study cannot be imported").await;
+        return Ok(());
+    }
+    let study_dir = import_dir.path.join(&study_id);
+    let Some(destination) = pacs.destination_for_room(&import.room_id).cloned() else {
+        error!("No PACS destination configured for room {}", import.room_id);
+        post(room, "No PACS destination configured for this room").await;
+        return Ok(());
+    };
+    // Whatever is not Send, keep only the message
+    let key = match parse_key(&import.hex_key) {
+        Ok(key) => key,
+        Err(e) => {
+            post(room, &format!("Invalid study key: {}", e)).await;
+            return Ok(());
+        }
+    };
+
+    if import.stage == ImportStage::Storing && !study_dir.is_dir() {
+        // removed once every instance was stored, or by hand; downloading
+        // again stores it twice at worst, rather than not at all
+        warn!("Downloaded files of study {} are missing, downloading it again", study_id);
+        import.stage = advance(jobs, job_id, &import, ImportStage::Downloading)?;
+    }
+    if import.stage < ImportStage::Storing {
+        let manifest = fetch_manifest(&study_id, &key, &*object_store)
+            .await
+            .map_err(|e| snafu::Report::from_error(e).to_string());
+        let manifest = match manifest {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                error!("Could not fetch manifest of study {}: {}", study_id, e);
+                return Err(format!("Could not verify study: {}", e));
+            }
+        };
+        match &manifest {
+            Some(manifest) if import.stage == ImportStage::Received => post(room, &preview(manifest)).await,
+            Some(_) => {}
+            None if pacs.import_without_manifest => {
+                warn!("Study {} has no manifest, importing all of its objects unverified", study_id)
+            }
+            None => {
+                // uploaded last, the study may still be uploading
+                error!("Study {} has no manifest", study_id);
+                return Err("Could not verify study: it has no manifest".to_string());
+            }
+        }
+        import.stage = advance(jobs, job_id, &import, ImportStage::Downloading)?;
+
+        let downloaded = download_study(&study_id, &key, manifest.as_ref(), &*object_store, &study_dir)
+            .await
+            .map_err(|e| snafu::Report::from_error(e).to_string());
+        if let Err(e) = downloaded {
+            // verified instances are kept for the next attempt
+            error!("Could not download study {}: {}", study_id, e);
+            return Err(format!("Could not download study: {}", e));
+        }
+        import.stage = advance(jobs, job_id, &import, ImportStage::Storing)?;
+    }
+
+    let dicom_files = downloaded_files(&study_dir).map_err(|e| format!("Could not read downloaded study: {}", e))?;
+    let (progress_tx, mut progress_rx) = mpsc::unbounded_channel();
+    let transfer = tokio::spawn(send_async(dicom_files, destination.clone(), progress_tx));
+    let mut reported_quarter = 0;
+    while let Some(progress) = progress_rx.recv().await {
+        let text = match progress {
+            SendProgress::Started { total } => {
+                format!("Transfering study to workstation ({} instances)...", total)
+            }
+            SendProgress::Instance { done, total, result } => {
+                debug!("{}/{} {}: {:?}", done, total, result.sop_instance_uid, result.status);
+                let quarter = done * 4 / total;
+                if quarter == reported_quarter || done == total {
+                    continue;
+                }
+                reported_quarter = quarter;
+                format!("{} of {} instances transferred", done, total)
+            }
+        };
+        post(room, &text).await;
+    }
+
+    match transfer.await {
+        Ok(Ok(report)) => {
+            for instance in &report.instances {
+                if let InstanceStatus::Failed(_) | InstanceStatus::NotSent(_) = instance.status {
+                    error!("Could not import {}: {:?}", instance.sop_instance_uid, instance.status);
+                }
+            }
+            if !import_dir.keep_files {
+                for instance in &report.instances {
+                    if let InstanceStatus::Stored | InstanceStatus::Warning(_) = instance.status {
+                        if let Err(e) = fs::remove_file(&instance.file) {
+                            warn!("Could not delete {}: {}", instance.file.display(), e);
+                        }
                     }
                 }
-            },
-            Err(E) => println!("{}",E.to_string())
+                // only succeeds once every instance was stored
+                let _ = fs::remove_dir(&study_dir);
+            }
+            post(room, &format!(
+                "Transferred {} of {} instances to {} ({} with warnings, {} failed)",
+                report.stored(), report.instances.len(), destination.ae_title, report.warnings(), report.failed()
+            )).await;
+            let unsent = report.instances.len() - report.stored();
+            if unsent > 0 {
+                return Err(format!("{} instances were not stored in {}", unsent, destination.ae_title));
+            }
+            Ok(())
+        }
+        Ok(Err(e)) => {
+            error!("Could not transfer study to {}: {}", destination.ae_title, snafu::Report::from_error(&e));
+            Err(format!("Could not transfer study to {}: {}", destination.ae_title, e))
+        }
+        Err(e) => {
+            error!("Study transfer to {} aborted: {}", destination.ae_title, e);
+            Err(format!("Study transfer to {} aborted", destination.ae_title))
         }
-        send(dicom_files);
-        // // download all files sequentially
-        //
-        // // download all files
-        //
-        let content = RoomMessageEventContent::text_plain("Transfering study to workstation...");
-        room.send(content, None).await.unwrap();
     }
 }
 
+/// Saves that `import` reached `stage` in job `job_id`.
+fn advance(jobs: &JobQueue, job_id: &str, import: &Import, stage: ImportStage) -> Result<ImportStage, String> {
+    let import = Import { stage, ..import.clone() };
+    jobs.set_task(job_id, Task::Import(import))
+        .map_err(|e| format!("Could not save import progress: {}", snafu::Report::from_error(e)))?;
+    Ok(stage)
+}
+
+/// Decrypts the downloaded `encrypted_file` into `dicom_file` and checks
+/// it against its manifest `entry`. Only complete, authenticated instances
+/// get their final name.
+fn decrypt_download(encrypted_file: &Path, dicom_file: &Path, key: &secretbox::Key, entry: Option<&ManifestEntry>) -> Result<(), Whatever> {
+    let input = File::open(encrypted_file)
+        .with_whatever_context(|_| format!("could not open {}", encrypted_file.display()))?;
+    let partial_file = dicom_file.with_extension("partial");
+    let output = File::create(&partial_file)
+        .with_whatever_context(|_| format!("could not create {}", partial_file.display()))?;
+    let mut output = Digesting::new(BufWriter::new(output));
+    if let Err(e) = decrypt_stream(BufReader::new(input), &mut output, key, entry.map(|entry| entry.size)) {
+        let _ = fs::remove_file(&partial_file);
+        return Err(e).whatever_context("invalid encrypted instance");
+    }
+    let (_, sha256, size) = output.finish();
+    if let Some(entry) = entry {
+        if entry.sha256 != sha256 || entry.size != size {
+            let _ = fs::remove_file(&partial_file);
+            whatever!("instance {} does not match the manifest", entry.sop_instance_uid);
+        }
+    }
+    fs::rename(&partial_file, dicom_file)
+        .with_whatever_context(|_| format!("could not write {}", dicom_file.display()))
+}
+
+/// Decrypted instances in `study_dir` which were not stored yet.
+fn downloaded_files(study_dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(study_dir)? {
+        let path = entry?.path();
+        if path.extension() == Some("dcm".as_ref()) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Downloads the objects of a study and writes their decrypted content to
+/// `study_dir`.
+async fn download_study(
+    study_id: &str,
+    key: &secretbox::Key,
+    manifest: Option<&Manifest>,
+    object_store: &dyn ObjectStore,
+    study_dir: &Path,
+) -> Result<Vec<PathBuf>, Whatever> {
+    let objects = object_store
+        .list(study_id)
+        .await
+        .whatever_context("could not list study objects")?;
+    let object_keys: Vec<String> = match manifest {
+        Some(manifest) => {
+            let missing = manifest
+                .instances
+                .iter()
+                .filter(|i| !objects.iter().any(|o| o.key == i.object_key))
+                .count();
+            ensure_whatever!(missing == 0,
+                "study is incomplete, {} of {} instances are missing", missing, manifest.instances.len());
+            manifest.instances.iter().map(|i| i.object_key.clone()).collect()
+        }
+        None => {
+            let manifest_key = manifest_key(study_id);
+            objects.into_iter().map(|o| o.key).filter(|k| *k != manifest_key).collect()
+        }
+    };
+
+    fs::create_dir_all(study_dir)
+        .with_whatever_context(|_| format!("could not create {}", study_dir.display()))?;
+    let mut dicom_files = vec![];
+    for object_key in object_keys {
+        let file_name = object_key.rsplit('/').next().unwrap_or(&object_key);
+        let dicom_file = study_dir.join(format!("{}.dcm", file_name));
+        if dicom_file.exists() {
+            // verified and decrypted by an earlier attempt
+            dicom_files.push(dicom_file);
+            continue;
+        }
+        let encrypted_file = dicom_file.with_extension("encrypted");
+        if let Err(e) = object_store.get_to_file(&object_key, &encrypted_file).await {
+            let _ = fs::remove_file(&encrypted_file);
+            return Err(e).with_whatever_context(|_| format!("could not download {}", object_key));
+        }
+        let entry = manifest.and_then(|m| m.instances.iter().find(|i| i.object_key == object_key)).cloned();
+        let (key, target) = (key.clone(), dicom_file.clone());
+        // Whatever is not Send, keep only the message
+        let decrypted = tokio::task::spawn_blocking(move || {
+            let decrypted = decrypt_download(&encrypted_file, &target, &key, entry.as_ref());
+            let _ = fs::remove_file(&encrypted_file);
+            decrypted.map_err(|e| snafu::Report::from_error(e).to_string())
+        })
+        .await;
+        match decrypted {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => whatever!("could not decrypt {}: {}", object_key, e),
+            Err(e) => whatever!("decryption of {} aborted: {}", object_key, e),
+        }
+        dicom_files.push(dicom_file);
+    }
+    Ok(dicom_files)
+}
+
+/// Fetches the manifest of a study, `None` if it was not uploaded (yet).
+async fn fetch_manifest(study_id: &str, key: &secretbox::Key, object_store: &dyn ObjectStore) -> Result<Option<Manifest>, Whatever> {
+    match object_store.get(&manifest_key(study_id)).await {
+        Ok(encrypted) => Ok(Some(Manifest::decrypt(&encrypted, key)?)),
+        Err(object_store::Error::NotFound { .. }) => Ok(None),
+        Err(e) => Err(e).whatever_context("could not download manifest"),
+    }
+}
+
+/// Summary of a study shown before it is imported.
+fn preview(manifest: &Manifest) -> String {
+    format!(
+        "Study of {} for {} ({}): {} series, {} instances ({} MB)\nSent by {} via {}",
+        manifest.study_date,
+        manifest.patient_name,
+        manifest.patient_id,
+        manifest.series_count(),
+        manifest.instances.len(),
+        manifest.total_size() / 1_000_000,
+        manifest.senders().into_iter().collect::<Vec<_>>().join(", "),
+        manifest.gateway,
+    )
+}
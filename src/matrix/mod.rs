@@ -1 +1,2 @@
-pub mod client;
\ No newline at end of file
+pub mod client;
+pub mod share;
\ No newline at end of file
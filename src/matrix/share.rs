@@ -0,0 +1,71 @@
+//! The line of an announcement which shares a study, `Key:<study hash>/<hex key>`.
+
+use snafu::{ensure_whatever, whatever, OptionExt, Whatever};
+
+use crate::studies::crypto::parse_key;
+
+const KEY_PREFIX: &str = "Key:";
+/// Hex encoded SHA-256 the objects of a study are stored below
+const STUDY_HASH_LEN: usize = 64;
+
+/// A study shared in an announcement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Share {
+    pub study_hash: String,
+    pub hex_key: String,
+}
+
+/// The line announcing the share of the study stored below `study_hash`.
+pub fn key_line(study_hash: &str, hex_key: &str) -> String {
+    format!("{}{}/{}", KEY_PREFIX, study_hash, hex_key)
+}
+
+/// The share announced in `body`, `None` if it does not share a study.
+pub fn parse_share(body: &str) -> Result<Option<Share>, Whatever> {
+    let Some(line) = body.lines().find_map(|line| line.trim().strip_prefix(KEY_PREFIX)) else {
+        return Ok(None);
+    };
+    let (study_hash, hex_key) = line.trim().split_once('/').whatever_context("share has no study key")?;
+    ensure_whatever!(is_study_hash(study_hash), "invalid study hash {:?}", study_hash);
+    if let Err(e) = parse_key(hex_key) {
+        whatever!("invalid study key: {}", e);
+    }
+    Ok(Some(Share { study_hash: study_hash.to_string(), hex_key: hex_key.to_string() }))
+}
+
+/// Whether `study_hash` is a hex encoded SHA-256, which is safe to use as
+/// a file name and an object key prefix.
+pub fn is_study_hash(study_hash: &str) -> bool {
+    study_hash.len() == STUDY_HASH_LEN && study_hash.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "6c9a4b1f0e3d2c7b8a9f0e1d2c3b4a5f6e7d8c9b0a1f2e3d4c5b6a7f8e9d0c1b";
+    const KEY: &str = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+
+    #[test]
+    fn parses_the_key_line_of_announcements() {
+        let body = format!("PatientenID:42\nName:Doe^John\nSerien:1\nBilder:3\n{}", key_line(HASH, KEY));
+        let share = parse_share(&body).unwrap().unwrap();
+        assert_eq!(share, Share { study_hash: HASH.to_string(), hex_key: KEY.to_string() });
+        assert_eq!(parse_share("just chatting").unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_malformed_shares() {
+        for line in [
+            "Key:".to_string(),
+            format!("Key:{}", HASH),
+            format!("Key:../../etc/{}", KEY),
+            format!("Key:{}/{}", &HASH[1..], KEY),
+            format!("Key:{}/{}", HASH.to_uppercase(), KEY),
+            format!("Key:{}/{}", HASH, &KEY[2..]),
+            format!("Key:{}/not-a-key", HASH),
+        ] {
+            assert!(parse_share(&line).is_err(), "{}", line);
+        }
+    }
+}
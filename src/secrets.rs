@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+
+use microkv::MicroKV;
+use serde_derive::Deserialize;
+use snafu::{ensure_whatever, OptionExt, ResultExt, Whatever};
+
+/// Prefix of environment variables read by [`EnvSecrets`],
+/// e.g. `DCMSHARE_SECRET_S3_SECRET_KEY` holds `s3_secret_key`.
+pub const ENV_PREFIX: &str = "DCMSHARE_SECRET_";
+
+/// Environment variable holding the keystore passphrase. If it is not set
+/// the passphrase is read from stdin at startup.
+const KEYSTORE_PASSPHRASE_ENV: &str = "DCMSHARE_SECRET_KEYSTORE_PASSPHRASE";
+
+pub const DB_PASSWORD: &str = "db_password";
+pub const MATRIX_PASSWORD: &str = "matrix_password";
+pub const S3_ACCESS_KEY: &str = "s3_access_key";
+pub const S3_SECRET_KEY: &str = "s3_secret_key";
+
+/// Source of credentials needed by the gateway components.
+pub trait SecretProvider: Send + Sync {
+    /// Name of the backend, used in error messages.
+    fn name(&self) -> &'static str;
+
+    fn get(&self, secret: &str) -> Result<Option<String>, Whatever>;
+
+    /// Like [`SecretProvider::get`] but fails if the secret is not available.
+    fn require(&self, secret: &str) -> Result<String, Whatever> {
+        self.get(secret)?
+            .with_whatever_context(|| format!("secret {} is not provided by the {} secret provider", secret, self.name()))
+    }
+}
+
+/// `[secrets]` section of the gateway configuration.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecretsConfig {
+    pub provider: ProviderKind,
+    /// Secrets file for the `file` provider, keystore directory for `keystore`.
+    pub path: PathBuf,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    Env,
+    File,
+    Keystore,
+}
+
+impl Default for SecretsConfig {
+    fn default() -> Self {
+        SecretsConfig {
+            provider: ProviderKind::Env,
+            path: PathBuf::from(""),
+        }
+    }
+}
+
+impl SecretsConfig {
+    pub fn provider(&self) -> Result<Box<dyn SecretProvider>, Whatever> {
+        Ok(match self.provider {
+            ProviderKind::Env => Box::new(EnvSecrets),
+            ProviderKind::File => Box::new(FileSecrets::open(&self.path)?),
+            ProviderKind::Keystore => Box::new(KeystoreSecrets::unlock(&self.path, &keystore_passphrase()?)?),
+        })
+    }
+}
+
+/// Reads secrets from `DCMSHARE_SECRET_<NAME>` environment variables.
+pub struct EnvSecrets;
+
+impl SecretProvider for EnvSecrets {
+    fn name(&self) -> &'static str {
+        "env"
+    }
+
+    fn get(&self, secret: &str) -> Result<Option<String>, Whatever> {
+        Ok(std::env::var(format!("{}{}", ENV_PREFIX, secret.to_uppercase())).ok())
+    }
+}
+
+/// Reads secrets from a TOML file of `name = "value"` pairs which must not be
+/// accessible by anyone but its owner.
+pub struct FileSecrets {
+    secrets: HashMap<String, String>,
+}
+
+impl FileSecrets {
+    pub fn open(path: &Path) -> Result<FileSecrets, Whatever> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(path)
+                .with_whatever_context(|_| format!("could not access secrets file {}", path.display()))?
+                .permissions()
+                .mode();
+            ensure_whatever!(mode & 0o077 == 0,
+                "secrets file {} must only be accessible by its owner (mode is {:o})", path.display(), mode & 0o777);
+        }
+        let content = fs::read_to_string(path)
+            .with_whatever_context(|_| format!("could not read secrets file {}", path.display()))?;
+        let secrets = toml::from_str(&content)
+            .with_whatever_context(|_| format!("could not parse secrets file {}", path.display()))?;
+        Ok(FileSecrets { secrets })
+    }
+}
+
+impl SecretProvider for FileSecrets {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn get(&self, secret: &str) -> Result<Option<String>, Whatever> {
+        Ok(self.secrets.get(secret).cloned())
+    }
+}
+
+/// Encrypted local keystore, unlocked with a passphrase at startup.
+pub struct KeystoreSecrets {
+    db: MicroKV,
+}
+
+impl KeystoreSecrets {
+    pub fn unlock(dir: &Path, passphrase: &str) -> Result<KeystoreSecrets, Whatever> {
+        ensure_whatever!(!passphrase.is_empty(), "keystore passphrase must not be empty");
+        let db = MicroKV::open_with_base_path("keystore", dir.to_path_buf())
+            .whatever_context("could not open keystore")?
+            .set_auto_commit(true)
+            .with_pwd_clear(passphrase);
+        Ok(KeystoreSecrets { db })
+    }
+
+    pub fn store(&self, secret: &str, value: &str) -> Result<(), Whatever> {
+        self.db
+            .put(secret, &value.to_string())
+            .with_whatever_context(|_| format!("could not store secret {}", secret))
+    }
+}
+
+impl SecretProvider for KeystoreSecrets {
+    fn name(&self) -> &'static str {
+        "keystore"
+    }
+
+    fn get(&self, secret: &str) -> Result<Option<String>, Whatever> {
+        if !self.db.exists(secret).whatever_context("could not read keystore")? {
+            return Ok(None);
+        }
+        self.db
+            .get_unwrap(secret)
+            .map(Some)
+            .with_whatever_context(|_| format!("could not decrypt secret {}, wrong keystore passphrase?", secret))
+    }
+}
+
+pub fn keystore_passphrase() -> Result<String, Whatever> {
+    if let Ok(passphrase) = std::env::var(KEYSTORE_PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    eprintln!("Keystore passphrase:");
+    read_line()
+}
+
+/// Reads a single line from stdin without the line terminator.
+pub fn read_line() -> Result<String, Whatever> {
+    let mut line = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut line)
+        .whatever_context("could not read from stdin")?;
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_secrets_from_the_environment() {
+        std::env::set_var("DCMSHARE_SECRET_TEST_ENV_SECRET", "from env");
+        assert_eq!(EnvSecrets.get("test_env_secret").unwrap().as_deref(), Some("from env"));
+        assert_eq!(EnvSecrets.get("test_env_missing").unwrap(), None);
+        assert!(EnvSecrets.require("test_env_missing").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_secrets_files_readable_by_others() {
+        use std::os::unix::fs::PermissionsExt;
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), "db_password = \"secret\"\n").unwrap();
+
+        fs::set_permissions(file.path(), fs::Permissions::from_mode(0o640)).unwrap();
+        assert!(FileSecrets::open(file.path()).is_err());
+        fs::set_permissions(file.path(), fs::Permissions::from_mode(0o604)).unwrap();
+        assert!(FileSecrets::open(file.path()).is_err());
+
+        fs::set_permissions(file.path(), fs::Permissions::from_mode(0o600)).unwrap();
+        let secrets = FileSecrets::open(file.path()).unwrap();
+        assert_eq!(secrets.require(DB_PASSWORD).unwrap(), "secret");
+        assert_eq!(secrets.get(MATRIX_PASSWORD).unwrap(), None);
+    }
+
+    #[test]
+    fn keystore_secrets_survive_unlocking_again() {
+        let dir = tempfile::tempdir().unwrap();
+        KeystoreSecrets::unlock(dir.path(), "passphrase").unwrap()
+            .store(S3_SECRET_KEY, "s3 secret")
+            .unwrap();
+
+        let keystore = KeystoreSecrets::unlock(dir.path(), "passphrase").unwrap();
+        assert_eq!(keystore.get(S3_SECRET_KEY).unwrap().as_deref(), Some("s3 secret"));
+        assert_eq!(keystore.get(S3_ACCESS_KEY).unwrap(), None);
+
+        let keystore = KeystoreSecrets::unlock(dir.path(), "wrong").unwrap();
+        assert!(keystore.get(S3_SECRET_KEY).is_err());
+        assert!(KeystoreSecrets::unlock(dir.path(), "").is_err());
+    }
+}
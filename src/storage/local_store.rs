@@ -0,0 +1,223 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+
+use async_trait::async_trait;
+use snafu::{ensure, ResultExt};
+use walkdir::WalkDir;
+
+use crate::storage::object_store::{Error, InvalidKeySnafu, IoSnafu, NotFoundSnafu, ObjectInfo, ObjectStore};
+
+/// [`ObjectStore`] keeping objects as files below a local directory,
+/// e.g. a NAS share mounted by both gateways of an air-gapped site.
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(root: &Path) -> Result<LocalStore, Error> {
+        fs::create_dir_all(root).context(IoSnafu { path: root })?;
+        Ok(LocalStore {
+            root: root.to_path_buf(),
+        })
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, Error> {
+        let relative = Path::new(key);
+        ensure!(
+            !key.is_empty() && relative.components().all(|c| matches!(c, Component::Normal(_))),
+            InvalidKeySnafu { key }
+        );
+        Ok(self.root.join(relative))
+    }
+
+    /// Directory the objects starting with `prefix` are in.
+    fn prefix_dir(&self, prefix: &str) -> Result<PathBuf, Error> {
+        let Some((dir, _)) = prefix.rsplit_once('/') else {
+            return Ok(self.root.clone());
+        };
+        self.path(dir).map_err(|_| InvalidKeySnafu { key: prefix }.build())
+    }
+}
+
+/// Key of the object at `path` below `root`.
+fn object_key(root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?;
+    let parts: Option<Vec<&str>> = relative.components().map(|c| c.as_os_str().to_str()).collect();
+    Some(parts?.join("/"))
+}
+
+/// Objects below `dir` whose key starts with `prefix`.
+fn list_dir(root: &Path, dir: &Path, prefix: &str) -> Result<Vec<ObjectInfo>, Error> {
+    let mut objects = vec![];
+    for entry in WalkDir::new(dir).sort_by_file_name() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) if e.io_error().map(|e| e.kind()) == Some(ErrorKind::NotFound) => continue,
+            Err(e) => {
+                let path = e.path().unwrap_or(dir).to_path_buf();
+                return Err(std::io::Error::from(e)).context(IoSnafu { path });
+            }
+        };
+        if !entry.file_type().is_file() || entry.path().extension() == Some("partial".as_ref()) {
+            continue;
+        }
+        let Some(key) = object_key(root, entry.path()) else { continue };
+        if key.starts_with(prefix) {
+            let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+            objects.push(ObjectInfo { key, size });
+        }
+    }
+    Ok(objects)
+}
+
+/// Temporary name objects are written under, so readers never see partial
+/// objects.
+fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    PathBuf::from(partial)
+}
+
+#[async_trait]
+impl ObjectStore for LocalStore {
+    async fn put(&self, key: &str, data: &[u8]) -> Result<(), Error> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await.context(IoSnafu { path: parent })?;
+        }
+        let partial = partial_path(&path);
+        tokio::fs::write(&partial, data).await.context(IoSnafu { path: &partial })?;
+        tokio::fs::rename(&partial, &path).await.context(IoSnafu { path: &path })?;
+        Ok(())
+    }
+
+    async fn put_file(&self, key: &str, source: &Path) -> Result<(), Error> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await.context(IoSnafu { path: parent })?;
+        }
+        let partial = partial_path(&path);
+        tokio::fs::copy(source, &partial).await.context(IoSnafu { path: &partial })?;
+        tokio::fs::rename(&partial, &path).await.context(IoSnafu { path: &path })?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, Error> {
+        let path = self.path(key)?;
+        match tokio::fs::read(&path).await {
+            Ok(data) => Ok(data),
+            Err(e) if e.kind() == ErrorKind::NotFound => NotFoundSnafu { key }.fail(),
+            Err(e) => Err(e).context(IoSnafu { path }),
+        }
+    }
+
+    async fn get_to_file(&self, key: &str, target: &Path) -> Result<(), Error> {
+        let path = self.path(key)?;
+        match tokio::fs::copy(&path, target).await {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound && !path.exists() => NotFoundSnafu { key }.fail(),
+            Err(e) => Err(e).context(IoSnafu { path: target }),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, Error> {
+        let dir = self.prefix_dir(prefix)?;
+        let (root, prefix) = (self.root.clone(), prefix.to_string());
+        let listing = tokio::task::spawn_blocking(move || list_dir(&root, &dir, &prefix));
+        match listing.await {
+            Ok(objects) => objects,
+            Err(e) => Err(std::io::Error::other(e)).context(IoSnafu { path: &self.root }),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        let path = self.path(key)?;
+        match tokio::fs::remove_file(&path).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e).context(IoSnafu { path }),
+            _ => Ok(()),
+        }
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<ObjectInfo>, Error> {
+        let path = self.path(key)?;
+        match tokio::fs::metadata(&path).await {
+            Ok(metadata) if metadata.is_file() => Ok(Some(ObjectInfo {
+                key: key.to_string(),
+                size: metadata.len(),
+            })),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).context(IoSnafu { path }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(objects: &[ObjectInfo]) -> Vec<&str> {
+        objects.iter().map(|o| o.key.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn put_get_head_delete_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalStore::new(&dir.path().join("objects")).unwrap();
+        store.put("study/instance", b"encrypted").await.unwrap();
+        assert_eq!(store.get("study/instance").await.unwrap(), b"encrypted");
+        let info = store.head("study/instance").await.unwrap().unwrap();
+        assert_eq!((info.key.as_str(), info.size), ("study/instance", 9));
+
+        store.put("study/instance", b"replaced").await.unwrap();
+        assert_eq!(store.get("study/instance").await.unwrap(), b"replaced");
+
+        store.delete("study/instance").await.unwrap();
+        assert!(matches!(store.get("study/instance").await, Err(Error::NotFound { .. })));
+        assert!(store.head("study/instance").await.unwrap().is_none());
+        store.delete("study/instance").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn copies_files_in_and_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalStore::new(&dir.path().join("objects")).unwrap();
+        let source = dir.path().join("instance");
+        fs::write(&source, b"encrypted").unwrap();
+        store.put_file("study/instance", &source).await.unwrap();
+        assert_eq!(store.get("study/instance").await.unwrap(), b"encrypted");
+        assert!(source.exists());
+
+        let target = dir.path().join("downloaded");
+        store.get_to_file("study/instance", &target).await.unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"encrypted");
+        assert!(matches!(store.get_to_file("study/other", &target).await, Err(Error::NotFound { .. })));
+    }
+
+    #[tokio::test]
+    async fn lists_objects_by_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalStore::new(dir.path()).unwrap();
+        for key in ["study1/b", "study1/a", "study10/c", "study2/manifest"] {
+            store.put(key, key.as_bytes()).await.unwrap();
+        }
+        fs::write(dir.path().join("study1/d.partial"), b"incomplete").unwrap();
+
+        assert_eq!(keys(&store.list("study1/").await.unwrap()), ["study1/a", "study1/b"]);
+        assert_eq!(keys(&store.list("study1").await.unwrap()), ["study1/a", "study1/b", "study10/c"]);
+        assert_eq!(keys(&store.list("").await.unwrap()).len(), 4);
+        assert!(store.list("study3/").await.unwrap().is_empty());
+        assert_eq!(store.list("study2/").await.unwrap()[0].size, 15);
+        assert!(matches!(store.list("../study1/").await, Err(Error::InvalidKey { .. })));
+    }
+
+    #[tokio::test]
+    async fn rejects_keys_outside_the_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalStore::new(&dir.path().join("objects")).unwrap();
+        for key in ["", "../escape", "/absolute", "study/../../escape", "./study"] {
+            assert!(matches!(store.put(key, b"data").await, Err(Error::InvalidKey { .. })), "{:?}", key);
+        }
+    }
+}
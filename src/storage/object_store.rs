@@ -0,0 +1,75 @@
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use s3::error::S3Error;
+use snafu::Snafu;
+
+/// Size and key of an object held by an [`ObjectStore`].
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    pub key: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum Error {
+    /// Request to the S3 endpoint failed
+    #[snafu(display("S3 request for {} failed", key))]
+    S3 {
+        key: String,
+        source: S3Error,
+    },
+
+    /// Local storage directory could not be accessed
+    #[snafu(display("could not access {}", path.display()))]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    /// S3 endpoint answered without the expected data
+    #[snafu(display("invalid S3 response for {}", key))]
+    InvalidResponse {
+        key: String,
+    },
+
+    #[snafu(display("object {} does not exist", key))]
+    NotFound {
+        key: String,
+    },
+
+    /// Keys are relative `/` separated paths without `.` or `..` segments
+    #[snafu(display("invalid object key {:?}", key))]
+    InvalidKey {
+        key: String,
+    },
+}
+
+/// Storage the encrypted instances of shared studies are exchanged through.
+///
+/// Keys have the form `<study hash>/<file id>`, so listing by prefix
+/// yields all objects of a study.
+#[async_trait]
+pub trait ObjectStore: Send + Sync {
+    async fn put(&self, key: &str, data: &[u8]) -> Result<(), Error>;
+
+    /// Uploads the file at `path` without reading it into memory at once.
+    /// Stores may keep upload progress next to the file, so calling this
+    /// again after a failure continues where the upload stopped.
+    async fn put_file(&self, key: &str, path: &Path) -> Result<(), Error>;
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, Error>;
+
+    /// Downloads the object into the file at `path`, which is created or
+    /// truncated, without reading it into memory at once.
+    async fn get_to_file(&self, key: &str, path: &Path) -> Result<(), Error>;
+
+    /// All objects whose key starts with `prefix`.
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, Error>;
+
+    async fn delete(&self, key: &str) -> Result<(), Error>;
+
+    /// Metadata of the object, `None` if it does not exist.
+    async fn head(&self, key: &str) -> Result<Option<ObjectInfo>, Error>;
+}
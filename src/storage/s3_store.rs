@@ -0,0 +1,314 @@
+use std::fs::{self, File, Metadata};
+use std::future::Future;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use s3::command::{Command, Multipart};
+use s3::creds::Credentials;
+use s3::error::S3Error;
+use s3::request::Reqwest;
+use s3::request_trait::Request;
+use s3::serde_types::{CompleteMultipartUploadData, Part};
+use s3::{Bucket, Region};
+use serde_derive::{Deserialize, Serialize};
+use snafu::{ensure, OptionExt, ResultExt};
+use tokio::io::AsyncWriteExt;
+use tracing::warn;
+
+use crate::storage::object_store::{Error, InvalidResponseSnafu, IoSnafu, NotFoundSnafu, ObjectInfo, ObjectStore, S3Snafu};
+
+const CONTENT_TYPE: &str = "application/octet-stream";
+/// Files larger than this are uploaded in parts of this size. S3 requires
+/// parts of at least 5 MiB.
+const PART_SIZE: u64 = 8 << 20;
+/// Attempts per request before an upload is given up.
+const MAX_ATTEMPTS: u32 = 5;
+/// Delay before the first retry, doubled with every further attempt.
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Location and credentials of the S3 bucket encrypted studies are shared through.
+#[derive(Clone, Debug)]
+pub struct BucketConfig {
+    pub endpoint: String,
+    pub name: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+impl BucketConfig {
+    pub fn open(&self) -> Result<Bucket, S3Error> {
+        Ok(Bucket::new(&self.name, Region::Custom {region:"".to_owned(),endpoint: self.endpoint.clone()}, Credentials{
+            access_key: Some(self.access_key.clone()),
+            secret_key: Some(self.secret_key.clone()),
+            expiration: None,
+            security_token: None,
+            session_token: None,
+        })?.with_path_style())
+    }
+}
+
+/// [`ObjectStore`] backed by an S3 compatible bucket (AWS S3, MinIO, ...).
+pub struct S3Store {
+    bucket: Bucket,
+}
+
+impl S3Store {
+    pub fn new(config: &BucketConfig) -> Result<S3Store, S3Error> {
+        Ok(S3Store {
+            bucket: config.open()?,
+        })
+    }
+
+    async fn initiate_upload(&self, key: &str) -> Result<Option<String>, S3Error> {
+        let command = Command::InitiateMultipartUpload { content_type: CONTENT_TYPE };
+        let response = Reqwest::new(&self.bucket, key, command).response_data(false).await?;
+        check_status(response.status_code(), response.bytes())?;
+        let body = String::from_utf8(response.bytes().to_vec())?;
+        Ok(xml_element(&body, "UploadId").map(str::to_string))
+    }
+
+    /// Uploads one part and returns its ETag.
+    async fn put_part(&self, key: &str, upload_id: &str, part_number: u32, data: &[u8]) -> Result<String, S3Error> {
+        let command = Command::PutObject {
+            content: data,
+            multipart: Some(Multipart::new(part_number, upload_id)),
+            content_type: CONTENT_TYPE,
+        };
+        let response = Reqwest::new(&self.bucket, key, command).response_data(true).await?;
+        check_status(response.status_code(), response.bytes())?;
+        Ok(String::from_utf8(response.bytes().to_vec())?)
+    }
+
+    async fn complete_upload(&self, key: &str, state: &UploadState) -> Result<(), S3Error> {
+        let parts = state
+            .etags
+            .iter()
+            .enumerate()
+            .map(|(i, etag)| Part { part_number: i as u32 + 1, etag: etag.clone() })
+            .collect();
+        let command = Command::CompleteMultipartUpload {
+            upload_id: &state.upload_id,
+            data: CompleteMultipartUploadData { parts },
+        };
+        let response = Reqwest::new(&self.bucket, key, command).response_data(false).await?;
+        check_status(response.status_code(), response.bytes())
+    }
+
+    /// Uploads `path` in parts, continuing an upload interrupted earlier.
+    async fn put_multipart(&self, key: &str, path: &Path, metadata: &Metadata) -> Result<(), Error> {
+        let size = metadata.len();
+        let modified = metadata.modified().context(IoSnafu { path })?;
+        let state_path = UploadState::path(path);
+        let mut state = match UploadState::load(&state_path) {
+            Some(state) if state.key == key && state.size == size && state.modified == modified => state,
+            stale => {
+                if let Some(stale) = stale {
+                    // the file changed since, its parts are of no use
+                    if let Err(e) = self.bucket.abort_upload(&stale.key, &stale.upload_id).await {
+                        warn!("Could not abort stale upload of {}: {}", stale.key, e);
+                    }
+                }
+                let upload_id = retry(|| self.initiate_upload(key))
+                    .await
+                    .context(S3Snafu { key })?
+                    .context(InvalidResponseSnafu { key })?;
+                UploadState { key: key.to_string(), upload_id, size, modified, etags: vec![] }
+            }
+        };
+        state.save(&state_path)?;
+
+        let mut file = File::open(path).context(IoSnafu { path })?;
+        let part_count = size.div_ceil(PART_SIZE) as u32;
+        for part_number in state.etags.len() as u32 + 1..=part_count {
+            let mut part = Vec::with_capacity(PART_SIZE as usize);
+            file.seek(SeekFrom::Start((part_number - 1) as u64 * PART_SIZE))
+                .and_then(|_| (&mut file).take(PART_SIZE).read_to_end(&mut part))
+                .context(IoSnafu { path })?;
+            let etag = retry(|| self.put_part(key, &state.upload_id, part_number, &part))
+                .await
+                .map_err(|e| state.discard_if_gone(&state_path, e))
+                .context(S3Snafu { key })?;
+            ensure!(!etag.is_empty(), InvalidResponseSnafu { key });
+            state.etags.push(etag);
+            state.save(&state_path)?;
+        }
+
+        retry(|| self.complete_upload(key, &state))
+            .await
+            .map_err(|e| state.discard_if_gone(&state_path, e))
+            .context(S3Snafu { key })?;
+        let _ = fs::remove_file(&state_path);
+        Ok(())
+    }
+}
+
+/// Progress of a multipart upload, saved next to the uploaded file so an
+/// interrupted upload continues with the next part after a restart.
+#[derive(Serialize, Deserialize)]
+struct UploadState {
+    key: String,
+    upload_id: String,
+    /// Size and modification time of the file, which must not change
+    /// between attempts
+    size: u64,
+    modified: SystemTime,
+    /// ETags of the uploaded parts in order
+    etags: Vec<String>,
+}
+
+impl UploadState {
+    fn path(file: &Path) -> PathBuf {
+        let mut path = file.as_os_str().to_owned();
+        path.push(".upload");
+        PathBuf::from(path)
+    }
+
+    fn load(path: &Path) -> Option<UploadState> {
+        let data = fs::read(path).ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    fn save(&self, path: &Path) -> Result<(), Error> {
+        let data = serde_json::to_vec(self).expect("upload state is serializable");
+        fs::write(path, data).context(IoSnafu { path })
+    }
+
+    /// Removes the saved state if the upload no longer exists, e.g. because
+    /// it expired, so the next attempt starts a new one.
+    fn discard_if_gone(&self, path: &Path, err: S3Error) -> S3Error {
+        if is_not_found(&err) {
+            let _ = fs::remove_file(path);
+        }
+        err
+    }
+}
+
+fn is_not_found(err: &S3Error) -> bool {
+    matches!(err, S3Error::Http(404, _))
+}
+
+/// Client errors other than timeouts and throttling fail the same way when
+/// retried.
+fn is_transient(err: &S3Error) -> bool {
+    match err {
+        S3Error::Http(status, _) => !(400..500).contains(status) || *status == 408 || *status == 429,
+        _ => true,
+    }
+}
+
+fn check_status(status: u16, body: &[u8]) -> Result<(), S3Error> {
+    if (200..300).contains(&status) {
+        Ok(())
+    } else {
+        Err(S3Error::Http(status, String::from_utf8_lossy(body).into_owned()))
+    }
+}
+
+/// Runs `request` until it succeeds, backing off exponentially between
+/// attempts.
+async fn retry<T, F, Fut>(mut request: F) -> Result<T, S3Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, S3Error>>,
+{
+    let mut delay = RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        match request().await {
+            Err(e) if attempt < MAX_ATTEMPTS && is_transient(&e) => {
+                warn!("S3 request failed (attempt {} of {}), retrying: {}", attempt, MAX_ATTEMPTS, e);
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Text of the first `name` element of an XML response.
+fn xml_element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{}>", name);
+    let start = xml.find(&open)? + open.len();
+    let end = start + xml[start..].find(&format!("</{}>", name))?;
+    Some(&xml[start..end])
+}
+
+#[async_trait]
+impl ObjectStore for S3Store {
+    async fn put(&self, key: &str, data: &[u8]) -> Result<(), Error> {
+        self.bucket
+            .put_object(key, data)
+            .await
+            .context(S3Snafu { key })?;
+        Ok(())
+    }
+
+    async fn put_file(&self, key: &str, path: &Path) -> Result<(), Error> {
+        let metadata = fs::metadata(path).context(IoSnafu { path })?;
+        if metadata.len() > PART_SIZE {
+            return self.put_multipart(key, path, &metadata).await;
+        }
+        let data = fs::read(path).context(IoSnafu { path })?;
+        retry(|| async {
+            let response = self.bucket.put_object(key, &data).await?;
+            check_status(response.status_code(), response.bytes())
+        })
+        .await
+        .context(S3Snafu { key })
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, Error> {
+        match self.bucket.get_object(key).await {
+            Ok(data) => Ok(data.bytes().to_vec()),
+            Err(e) if is_not_found(&e) => NotFoundSnafu { key }.fail(),
+            Err(e) => Err(e).context(S3Snafu { key }),
+        }
+    }
+
+    async fn get_to_file(&self, key: &str, path: &Path) -> Result<(), Error> {
+        let mut file = tokio::fs::File::create(path).await.context(IoSnafu { path })?;
+        match self.bucket.get_object_stream(key, &mut file).await {
+            Ok(_) => file.flush().await.context(IoSnafu { path }),
+            Err(e) if is_not_found(&e) => NotFoundSnafu { key }.fail(),
+            Err(e) => Err(e).context(S3Snafu { key }),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, Error> {
+        let results = self
+            .bucket
+            .list(prefix.to_string(), None)
+            .await
+            .context(S3Snafu { key: prefix })?;
+        Ok(results
+            .into_iter()
+            .flat_map(|r| r.contents)
+            .map(|object| ObjectInfo {
+                key: object.key,
+                size: object.size,
+            })
+            .collect())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        self.bucket
+            .delete_object(key)
+            .await
+            .context(S3Snafu { key })?;
+        Ok(())
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<ObjectInfo>, Error> {
+        match self.bucket.head_object(key).await {
+            Ok((head, _)) => Ok(Some(ObjectInfo {
+                key: key.to_string(),
+                size: head.content_length.unwrap_or(0) as u64,
+            })),
+            Err(e) if is_not_found(&e) => Ok(None),
+            Err(e) => Err(e).context(S3Snafu { key }),
+        }
+    }
+}
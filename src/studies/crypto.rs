@@ -0,0 +1,305 @@
+//! Encryption of the instances exchanged through the object store.
+//!
+//! Objects start with a versioned header followed by the instance encrypted
+//! as a `secretstream` of fixed size chunks, so neither side has to hold a
+//! whole instance in memory:
+//!
+//! ```text
+//! "DCMS" | version (1) | chunk size (u32 LE) | secretstream header | chunks...
+//! ```
+//!
+//! Each chunk carries `chunk size` bytes of plaintext except the last one,
+//! which is tagged final. The format prefix is authenticated with every chunk.
+//! Objects without the magic are single `secretbox`es with the nonce as
+//! prefix, as uploaded by earlier versions.
+
+use std::io::{self, Read, Write};
+
+use sodiumoxide::crypto::{secretbox, secretstream};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+
+const MAGIC: &[u8; 4] = b"DCMS";
+const FORMAT_VERSION: u8 = 1;
+const FORMAT_PREFIX_BYTES: usize = MAGIC.len() + 1 + 4;
+/// Plaintext bytes per chunk of newly encrypted instances.
+const CHUNK_SIZE: usize = 1 << 20;
+/// Largest chunk size accepted when decrypting.
+const MAX_CHUNK_SIZE: usize = 64 << 20;
+
+/// Reasons an instance could not be encrypted or an encrypted instance could
+/// not be opened.
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum Error {
+    #[snafu(display("study key is not hex encoded"))]
+    KeyEncoding {
+        source: hex::FromHexError,
+    },
+
+    #[snafu(display("study key must be {} bytes, got {}", secretbox::KEYBYTES, len))]
+    KeyLength {
+        len: usize,
+    },
+
+    #[snafu(display("could not read or write instance data"))]
+    Io {
+        source: io::Error,
+    },
+
+    #[snafu(display("encryption failed"))]
+    Encrypt,
+
+    #[snafu(display("unsupported encryption format version {}", version))]
+    UnsupportedVersion {
+        version: u8,
+    },
+
+    /// The object ends inside or before the final chunk
+    #[snafu(display("encrypted instance is truncated"))]
+    Truncated,
+
+    /// The authentication tag does not match, the object was modified or
+    /// encrypted with a different key
+    #[snafu(display("encrypted instance failed authentication, it was tampered with or the key is wrong"))]
+    Tampered,
+}
+
+/// Decodes the hex encoded study key shared with the recipients.
+pub fn parse_key(hex_key: &str) -> Result<secretbox::Key, Error> {
+    let key_bytes = hex::decode(hex_key.trim()).context(KeyEncodingSnafu)?;
+    secretbox::Key::from_slice(&key_bytes).context(KeyLengthSnafu { len: key_bytes.len() })
+}
+
+/// Encrypts an instance read from `reader` into `writer` chunk by chunk.
+pub fn encrypt_stream(mut reader: impl Read, mut writer: impl Write, key: &secretbox::Key) -> Result<(), Error> {
+    let key = secretstream::Key::from_slice(&key.0).unwrap();
+    let (mut stream, header) = secretstream::Stream::init_push(&key).ok().context(EncryptSnafu)?;
+    let prefix = format_prefix(CHUNK_SIZE as u32);
+    writer.write_all(&prefix).context(IoSnafu)?;
+    writer.write_all(&header.0).context(IoSnafu)?;
+
+    let mut chunk = vec![0; CHUNK_SIZE];
+    let mut next_chunk = vec![0; CHUNK_SIZE];
+    let mut len = read_full(&mut reader, &mut chunk)?;
+    loop {
+        // read ahead to know whether this is the final chunk
+        let next_len = if len == CHUNK_SIZE { read_full(&mut reader, &mut next_chunk)? } else { 0 };
+        let tag = if next_len == 0 { secretstream::Tag::Final } else { secretstream::Tag::Message };
+        let ciphertext = stream.push(&chunk[..len], Some(&prefix), tag).ok().context(EncryptSnafu)?;
+        writer.write_all(&ciphertext).context(IoSnafu)?;
+        if tag == secretstream::Tag::Final {
+            break;
+        }
+        std::mem::swap(&mut chunk, &mut next_chunk);
+        len = next_len;
+    }
+    writer.flush().context(IoSnafu)
+}
+
+/// Decrypts an object written by [`encrypt_stream`] or an earlier version
+/// from `reader` into `writer`.
+///
+/// `size` is the plaintext size if known, e.g. from the manifest. Without
+/// it, a chunk cut short which fails authentication is taken for a
+/// truncation and a legacy object cut short for tampering.
+///
+/// Every chunk is authenticated before it is written, but a failure may
+/// occur after earlier chunks were written, so `writer` must be discarded
+/// on error.
+pub fn decrypt_stream(mut reader: impl Read, mut writer: impl Write, key: &secretbox::Key, size: Option<u64>) -> Result<(), Error> {
+    let mut prefix = [0; FORMAT_PREFIX_BYTES];
+    let prefix_len = read_full(&mut reader, &mut prefix)?;
+    if prefix_len < MAGIC.len() || &prefix[..MAGIC.len()] != MAGIC {
+        // single secretbox, which has to be read completely anyway
+        let mut encrypted = prefix[..prefix_len].to_vec();
+        reader.read_to_end(&mut encrypted).context(IoSnafu)?;
+        let data = open_secretbox(&encrypted, key, size)?;
+        return writer.write_all(&data).and_then(|_| writer.flush()).context(IoSnafu);
+    }
+    ensure!(prefix_len == FORMAT_PREFIX_BYTES, TruncatedSnafu);
+    let version = prefix[MAGIC.len()];
+    ensure!(version == FORMAT_VERSION, UnsupportedVersionSnafu { version });
+    let chunk_size = u32::from_le_bytes(prefix[MAGIC.len() + 1..].try_into().unwrap()) as usize;
+    ensure!(chunk_size > 0 && chunk_size <= MAX_CHUNK_SIZE, TamperedSnafu);
+
+    let mut header = [0; secretstream::HEADERBYTES];
+    ensure!(read_full(&mut reader, &mut header)? == header.len(), TruncatedSnafu);
+    let key = secretstream::Key::from_slice(&key.0).unwrap();
+    let mut stream = secretstream::Stream::init_pull(&secretstream::Header(header), &key)
+        .ok()
+        .context(TamperedSnafu)?;
+
+    let mut frame = vec![0; chunk_size + secretstream::ABYTES];
+    let mut remaining = size;
+    loop {
+        let len = read_full(&mut reader, &mut frame)?;
+        ensure!(len >= secretstream::ABYTES, TruncatedSnafu);
+        // only the final chunk may be short
+        let short = len < frame.len();
+        if short {
+            let chunk_len = (len - secretstream::ABYTES) as u64;
+            ensure!(!matches!(remaining, Some(remaining) if chunk_len < remaining), TruncatedSnafu);
+        }
+        let (data, tag) = match stream.pull(&frame[..len], Some(&prefix)) {
+            Ok(pulled) => pulled,
+            Err(()) if short && remaining.is_none() => return TruncatedSnafu.fail(),
+            Err(()) => return TamperedSnafu.fail(),
+        };
+        writer.write_all(&data).context(IoSnafu)?;
+        remaining = remaining.map(|remaining| remaining.saturating_sub(data.len() as u64));
+        if tag == secretstream::Tag::Final {
+            // nothing may follow the final chunk
+            ensure!(read_full(&mut reader, &mut [0; 1])? == 0, TamperedSnafu);
+            return writer.flush().context(IoSnafu);
+        }
+        ensure!(!short, TruncatedSnafu);
+    }
+}
+
+/// Decrypts an object held in memory, see [`decrypt_stream`].
+pub fn decrypt_instance(encrypted: &[u8], key: &secretbox::Key) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::with_capacity(encrypted.len());
+    decrypt_stream(encrypted, &mut data, key, None)?;
+    Ok(data)
+}
+
+fn format_prefix(chunk_size: u32) -> [u8; FORMAT_PREFIX_BYTES] {
+    let mut prefix = [0; FORMAT_PREFIX_BYTES];
+    prefix[..MAGIC.len()].copy_from_slice(MAGIC);
+    prefix[MAGIC.len()] = FORMAT_VERSION;
+    prefix[MAGIC.len() + 1..].copy_from_slice(&chunk_size.to_le_bytes());
+    prefix
+}
+
+fn open_secretbox(encrypted: &[u8], key: &secretbox::Key, size: Option<u64>) -> Result<Vec<u8>, Error> {
+    let min_len = secretbox::NONCEBYTES + secretbox::MACBYTES;
+    ensure!(encrypted.len() >= min_len, TruncatedSnafu);
+    ensure!(!matches!(size, Some(size) if ((encrypted.len() - min_len) as u64) < size), TruncatedSnafu);
+    let (nonce, ciphertext) = encrypted.split_at(secretbox::NONCEBYTES);
+    let nonce = secretbox::Nonce::from_slice(nonce).unwrap();
+    secretbox::open(ciphertext, &nonce, key).ok().context(TamperedSnafu)
+}
+
+/// Fills `buf` as far as `reader` has data, returns the number of bytes read.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e).context(IoSnafu),
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER_BYTES: usize = FORMAT_PREFIX_BYTES + secretstream::HEADERBYTES;
+
+    fn new_key() -> secretbox::Key {
+        sodiumoxide::init().unwrap();
+        secretbox::gen_key()
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn encrypt(data: &[u8], key: &secretbox::Key) -> Vec<u8> {
+        let mut encrypted = vec![];
+        encrypt_stream(data, &mut encrypted, key).unwrap();
+        encrypted
+    }
+
+    fn decrypt(encrypted: &[u8], key: &secretbox::Key, size: Option<u64>) -> Result<Vec<u8>, Error> {
+        let mut data = vec![];
+        decrypt_stream(encrypted, &mut data, key, size)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn encrypts_empty_input_as_a_final_chunk() {
+        let key = new_key();
+        let encrypted = encrypt(&[], &key);
+        assert_eq!(encrypted.len(), HEADER_BYTES + secretstream::ABYTES);
+        assert_eq!(decrypt(&encrypted, &key, Some(0)).unwrap(), Vec::<u8>::new());
+        assert!(matches!(decrypt(&encrypted[..HEADER_BYTES], &key, None), Err(Error::Truncated)));
+    }
+
+    #[test]
+    fn round_trips_exact_multiples_of_the_chunk_size() {
+        let key = new_key();
+        for chunks in [1, 2] {
+            let data = data(chunks * CHUNK_SIZE);
+            let encrypted = encrypt(&data, &key);
+            // the last full chunk is the final one, no empty chunk follows
+            assert_eq!(encrypted.len(), HEADER_BYTES + chunks * (CHUNK_SIZE + secretstream::ABYTES));
+            assert_eq!(decrypt(&encrypted, &key, None).unwrap(), data);
+            assert_eq!(decrypt(&encrypted, &key, Some(data.len() as u64)).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn reports_truncation_at_and_inside_chunks() {
+        let key = new_key();
+        let data = data(CHUNK_SIZE + 1000);
+        let encrypted = encrypt(&data, &key);
+        let size = Some(data.len() as u64);
+        let chunk_end = HEADER_BYTES + CHUNK_SIZE + secretstream::ABYTES;
+        for len in [chunk_end, chunk_end + 500, chunk_end - 500, encrypted.len() - 1, 5] {
+            assert!(matches!(decrypt(&encrypted[..len], &key, None), Err(Error::Truncated)), "{}", len);
+            assert!(matches!(decrypt(&encrypted[..len], &key, size), Err(Error::Truncated)), "{}", len);
+        }
+    }
+
+    #[test]
+    fn requires_the_final_tag() {
+        let key = new_key();
+        let prefix = format_prefix(CHUNK_SIZE as u32);
+        let stream_key = secretstream::Key::from_slice(&key.0).unwrap();
+        let (mut stream, header) = secretstream::Stream::init_push(&stream_key).unwrap();
+        let mut encrypted = prefix.to_vec();
+        encrypted.extend_from_slice(&header.0);
+        encrypted.extend(stream.push(&data(100), Some(&prefix), secretstream::Tag::Message).unwrap());
+        assert!(matches!(decrypt(&encrypted, &key, None), Err(Error::Truncated)));
+    }
+
+    #[test]
+    fn reports_flipped_bytes_as_tampering() {
+        let key = new_key();
+        let data = data(CHUNK_SIZE + 1000);
+        let encrypted = encrypt(&data, &key);
+        let size = Some(data.len() as u64);
+        for position in [HEADER_BYTES + 10, encrypted.len() - 10, FORMAT_PREFIX_BYTES + 1] {
+            let mut tampered = encrypted.clone();
+            tampered[position] ^= 1;
+            assert!(matches!(decrypt(&tampered, &key, size), Err(Error::Tampered)), "{}", position);
+        }
+        assert!(matches!(decrypt(&encrypted, &new_key(), size), Err(Error::Tampered)));
+        let mut appended = encrypted.clone();
+        appended.push(0);
+        assert!(matches!(decrypt(&appended, &key, size), Err(Error::Tampered)));
+    }
+
+    #[test]
+    fn decrypts_legacy_secretboxes() {
+        let key = new_key();
+        let data = data(5000);
+        let nonce = secretbox::gen_nonce();
+        let mut encrypted = nonce.0.to_vec();
+        encrypted.extend(secretbox::seal(&data, &nonce, &key));
+        let size = Some(data.len() as u64);
+        assert_eq!(decrypt(&encrypted, &key, None).unwrap(), data);
+        assert_eq!(decrypt_instance(&encrypted, &key).unwrap(), data);
+
+        assert!(matches!(decrypt(&encrypted[..encrypted.len() - 1], &key, size), Err(Error::Truncated)));
+        assert!(matches!(decrypt(&encrypted[..10], &key, None), Err(Error::Truncated)));
+        let mut tampered = encrypted.clone();
+        tampered[100] ^= 1;
+        assert!(matches!(decrypt(&tampered, &key, size), Err(Error::Tampered)));
+    }
+}
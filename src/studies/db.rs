@@ -0,0 +1,526 @@
+//! SQLite database of the studies shared by the gateway: their keys, the
+//! upload status of every instance, to whom they were announced and the
+//! job queue.
+//!
+//! Study keys are stored encrypted with a key derived from the database
+//! password with Argon2id and a random salt kept in the database. The schema
+//! is upgraded on open by running the migrations the database has not seen
+//! yet, tracked in `PRAGMA user_version`.
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use microkv::MicroKV;
+use rusqlite::{params, Connection, OptionalExtension, Transaction, TransactionBehavior};
+use snafu::{ensure_whatever, OptionExt, ResultExt, Whatever};
+use sodiumoxide::crypto::pwhash::argon2id13;
+use sodiumoxide::crypto::secretbox;
+use tracing::info;
+
+use crate::dcm::storescp::StoredInstance;
+use crate::studies::manifest::{unix_time, ManifestEntry};
+use crate::studies::store::Study;
+
+/// Schema migrations, `MIGRATIONS[n]` upgrades the schema from version `n`.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE studies (
+        study_instance_uid TEXT PRIMARY KEY,
+        study_hash TEXT,
+        encrypted_key BLOB NOT NULL,
+        study_date TEXT,
+        patient_id TEXT,
+        patient_name TEXT,
+        patient_birth_date TEXT,
+        created_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL
+    );
+    CREATE TABLE instances (
+        sop_instance_uid TEXT PRIMARY KEY,
+        study_instance_uid TEXT NOT NULL REFERENCES studies (study_instance_uid),
+        series_instance_uid TEXT NOT NULL,
+        sop_class_uid TEXT NOT NULL,
+        transfer_syntax_uid TEXT NOT NULL,
+        sender_ae_title TEXT NOT NULL,
+        object_key TEXT,
+        size INTEGER,
+        sha256 TEXT,
+        upload_status TEXT NOT NULL,
+        last_error TEXT,
+        received_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL
+    );
+    CREATE INDEX instances_by_study ON instances (study_instance_uid);
+    CREATE TABLE shares (
+        id INTEGER PRIMARY KEY,
+        study_instance_uid TEXT NOT NULL REFERENCES studies (study_instance_uid),
+        series_count INTEGER NOT NULL,
+        instance_count INTEGER NOT NULL,
+        shared_at INTEGER NOT NULL
+    );
+    CREATE INDEX shares_by_study ON shares (study_instance_uid);
+    CREATE TABLE recipients (
+        share_id INTEGER NOT NULL REFERENCES shares (id),
+        recipient TEXT NOT NULL,
+        PRIMARY KEY (share_id, recipient)
+    );
+    CREATE TABLE settings (
+        name TEXT PRIMARY KEY,
+        value BLOB NOT NULL
+    );",
+    "CREATE TABLE jobs (
+        id TEXT PRIMARY KEY,
+        kind TEXT NOT NULL,
+        study_instance_uid TEXT,
+        encrypted_task BLOB NOT NULL,
+        state TEXT NOT NULL,
+        attempts INTEGER NOT NULL,
+        next_attempt_at INTEGER NOT NULL,
+        last_error TEXT,
+        created_at INTEGER NOT NULL
+    );
+    CREATE INDEX jobs_by_due_date ON jobs (state, next_attempt_at);
+    CREATE INDEX jobs_by_study ON jobs (kind, study_instance_uid);",
+];
+
+/// How long to wait for the `jobs` commands, or the gateway, to release
+/// the database.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Plaintext sealed with the database key, which tells a wrong password
+/// apart from a damaged database.
+const KEY_CHECK: &[u8] = b"dcmshare study database";
+
+/// Upload status of an instance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadStatus {
+    Uploaded,
+    Failed,
+}
+
+impl UploadStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            UploadStatus::Uploaded => "uploaded",
+            UploadStatus::Failed => "failed",
+        }
+    }
+}
+
+/// A shared study as listed by [`StudyDb::history`].
+#[derive(Debug, Clone)]
+pub struct StudyRecord {
+    pub study_instance_uid: String,
+    pub study_date: String,
+    pub patient_id: String,
+    pub patient_name: String,
+    pub uploaded_instances: usize,
+    pub failed_instances: usize,
+    /// Recipients the study was announced to
+    pub recipients: Vec<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(Clone)]
+pub struct StudyDb {
+    conn: Arc<Mutex<Connection>>,
+    key: secretbox::Key,
+}
+
+impl StudyDb {
+    /// Opens `studies.sqlite` in `db_dir`, upgrades its schema and takes
+    /// over the keys of a study database written by earlier versions.
+    pub fn open(db_dir: &Path, db_password: &str) -> Result<StudyDb, Whatever> {
+        let _ = sodiumoxide::init();
+        let path = db_dir.join("studies.sqlite");
+        let mut conn = Connection::open(&path)
+            .with_whatever_context(|_| format!("could not open {}", path.display()))?;
+        conn.pragma_update(None, "foreign_keys", true)
+            .whatever_context("could not enable foreign keys")?;
+        conn.busy_timeout(BUSY_TIMEOUT).whatever_context("could not set busy timeout")?;
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))
+            .whatever_context("could not enable write-ahead logging")?;
+        migrate(&mut conn)?;
+        let key = database_key(&mut conn, db_password)?;
+        let db = StudyDb {
+            conn: Arc::new(Mutex::new(conn)),
+            key,
+        };
+        db.import_legacy(db_dir, db_password)?;
+        Ok(db)
+    }
+
+    /// Imports the study keys of the MicroKV database used before, which is
+    /// renamed afterwards so it is only imported once.
+    fn import_legacy(&self, db_dir: &Path, db_password: &str) -> Result<(), Whatever> {
+        let legacy_path = MicroKV::get_db_path_with_base_path("studies", db_dir.to_path_buf());
+        if !legacy_path.is_file() {
+            return Ok(());
+        }
+        let legacy = MicroKV::open_with_base_path("studies", db_dir.to_path_buf())
+            .whatever_context("could not open previous study key database")?
+            .with_pwd_clear(db_password);
+        let uids = legacy.keys().whatever_context("could not read previous study key database")?;
+        for uid in &uids {
+            let hex_key: String = legacy
+                .get_unwrap(uid)
+                .with_whatever_context(|_| format!("could not read key of study {}", uid))?;
+            self.study_key(uid, &hex_key)?;
+        }
+        std::fs::rename(&legacy_path, legacy_path.with_extension("kv.migrated"))
+            .with_whatever_context(|_| format!("could not rename {}", legacy_path.display()))?;
+        info!("Imported the keys of {} studies from {}", uids.len(), legacy_path.display());
+        Ok(())
+    }
+
+    /// Runs `f` on the connection.
+    pub(crate) fn read<T>(&self, f: impl FnOnce(&Connection) -> Result<T, Whatever>) -> Result<T, Whatever> {
+        f(&self.conn.lock().unwrap())
+    }
+
+    /// Runs `f` in a transaction which takes the write lock up front, so
+    /// that processes sharing the database never write what they read
+    /// before another one changed it.
+    pub(crate) fn write<T>(&self, f: impl FnOnce(&Transaction) -> Result<T, Whatever>) -> Result<T, Whatever> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .whatever_context("could not start transaction")?;
+        let value = f(&tx)?;
+        tx.commit().whatever_context("could not commit transaction")?;
+        Ok(value)
+    }
+
+    /// Encrypts `data` with the database key.
+    pub(crate) fn encrypt(&self, data: &[u8]) -> Vec<u8> {
+        seal(data, &self.key)
+    }
+
+    pub(crate) fn decrypt(&self, encrypted: &[u8]) -> Result<Vec<u8>, Whatever> {
+        unseal(encrypted, &self.key)
+    }
+
+    fn encrypt_key(&self, hex_key: &str) -> Vec<u8> {
+        seal(hex_key.as_bytes(), &self.key)
+    }
+
+    fn decrypt_key(&self, encrypted: &[u8]) -> Result<String, Whatever> {
+        let hex_key = unseal(encrypted, &self.key)
+            .whatever_context("could not decrypt study key, is the database password correct?")?;
+        String::from_utf8(hex_key).whatever_context("stored study key is not valid UTF-8")
+    }
+
+    /// Key of the study, `new_key` is stored for studies not seen before.
+    /// Returns the key and whether the study is new.
+    pub fn study_key(&self, study_instance_uid: &str, new_key: &str) -> Result<(String, bool), Whatever> {
+        let conn = self.conn.lock().unwrap();
+        let now = unix_time() as i64;
+        let inserted = conn
+            .execute(
+                "INSERT OR IGNORE INTO studies (study_instance_uid, encrypted_key, created_at, updated_at)
+                 VALUES (?1, ?2, ?3, ?3)",
+                params![study_instance_uid, self.encrypt_key(new_key), now],
+            )
+            .with_whatever_context(|_| format!("could not store key of study {}", study_instance_uid))?;
+        let encrypted: Vec<u8> = conn
+            .query_row(
+                "SELECT encrypted_key FROM studies WHERE study_instance_uid = ?1",
+                [study_instance_uid],
+                |row| row.get(0),
+            )
+            .with_whatever_context(|_| format!("could not read key of study {}", study_instance_uid))?;
+        Ok((self.decrypt_key(&encrypted)?, inserted > 0))
+    }
+
+    /// Updates the patient and study attributes of a study.
+    pub fn update_study(&self, study: &Study) -> Result<(), Whatever> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE studies SET study_hash = ?2, study_date = ?3, patient_id = ?4, patient_name = ?5,
+                     patient_birth_date = ?6, updated_at = ?7
+                 WHERE study_instance_uid = ?1",
+                params![
+                    study.study_instance_uid,
+                    study.study_instance_uid_hash,
+                    study.study_date,
+                    study.patient_id,
+                    study.patient_name,
+                    study.patient_birth_date,
+                    unix_time() as i64,
+                ],
+            )
+            .with_whatever_context(|_| format!("could not update study {}", study.study_instance_uid))?;
+        Ok(())
+    }
+
+    /// Records the outcome of the upload of `instance`, `entry` describes the
+    /// uploaded object.
+    pub fn record_upload(
+        &self,
+        study_instance_uid: &str,
+        instance: &StoredInstance,
+        entry: Option<&ManifestEntry>,
+        status: UploadStatus,
+        error: Option<&str>,
+    ) -> Result<(), Whatever> {
+        let now = unix_time() as i64;
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT INTO instances (sop_instance_uid, study_instance_uid, series_instance_uid, sop_class_uid,
+                     transfer_syntax_uid, sender_ae_title, object_key, size, sha256, upload_status, last_error,
+                     received_at, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?12)
+                 ON CONFLICT (sop_instance_uid) DO UPDATE SET
+                     object_key = coalesce(excluded.object_key, object_key),
+                     size = coalesce(excluded.size, size),
+                     sha256 = coalesce(excluded.sha256, sha256),
+                     upload_status = excluded.upload_status,
+                     last_error = excluded.last_error,
+                     updated_at = excluded.updated_at",
+                params![
+                    instance.sop_instance_uid,
+                    study_instance_uid,
+                    instance.series_instance_uid,
+                    instance.sop_class_uid,
+                    instance.transfer_syntax_uid,
+                    instance.calling_ae_title,
+                    entry.map(|e| &e.object_key),
+                    entry.map(|e| e.size as i64),
+                    entry.map(|e| &e.sha256),
+                    status.as_str(),
+                    error,
+                    now,
+                ],
+            )
+            .with_whatever_context(|_| format!("could not record upload of instance {}", instance.sop_instance_uid))?;
+        Ok(())
+    }
+
+    /// Records that `study` was announced to `recipients`.
+    pub fn record_share(&self, study: &Study, recipients: &[String]) -> Result<(), Whatever> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().whatever_context("could not record share")?;
+        tx.execute(
+            "INSERT INTO shares (study_instance_uid, series_count, instance_count, shared_at) VALUES (?1, ?2, ?3, ?4)",
+            params![study.study_instance_uid, study.series_count() as i64, study.instance_count as i64, unix_time() as i64],
+        )
+        .with_whatever_context(|_| format!("could not record share of study {}", study.study_instance_uid))?;
+        let share_id = tx.last_insert_rowid();
+        for recipient in recipients {
+            tx.execute("INSERT INTO recipients (share_id, recipient) VALUES (?1, ?2)", params![share_id, recipient])
+                .with_whatever_context(|_| format!("could not record recipient {}", recipient))?;
+        }
+        tx.commit().whatever_context("could not record share")
+    }
+
+    /// Instances of the largest share of the study announced to `recipient`,
+    /// `None` if it was never shared with it.
+    pub fn shared_with(&self, study_instance_uid: &str, recipient: &str) -> Result<Option<usize>, Whatever> {
+        let instance_count: Option<i64> = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT max(s.instance_count) FROM shares s JOIN recipients r ON r.share_id = s.id
+                 WHERE s.study_instance_uid = ?1 AND r.recipient = ?2",
+                params![study_instance_uid, recipient],
+                |row| row.get(0),
+            )
+            .optional()
+            .with_whatever_context(|_| format!("could not look up shares of study {}", study_instance_uid))?
+            .flatten();
+        Ok(instance_count.map(|count| count as usize))
+    }
+
+    /// The most recently updated studies, at most `limit`.
+    pub fn history(&self, limit: usize) -> Result<Vec<StudyRecord>, Whatever> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn
+            .prepare(
+                "SELECT st.study_instance_uid, coalesce(st.study_date, ''), coalesce(st.patient_id, ''),
+                     coalesce(st.patient_name, ''),
+                     (SELECT count(*) FROM instances i
+                      WHERE i.study_instance_uid = st.study_instance_uid AND i.upload_status = 'uploaded'),
+                     (SELECT count(*) FROM instances i
+                      WHERE i.study_instance_uid = st.study_instance_uid AND i.upload_status = 'failed'),
+                     (SELECT group_concat(DISTINCT r.recipient) FROM shares s JOIN recipients r ON r.share_id = s.id
+                      WHERE s.study_instance_uid = st.study_instance_uid),
+                     st.created_at, st.updated_at
+                 FROM studies st ORDER BY st.updated_at DESC, st.rowid DESC LIMIT ?1",
+            )
+            .whatever_context("could not query study history")?;
+        let rows = statement
+            .query_map([limit as i64], |row| {
+                let recipients: Option<String> = row.get(6)?;
+                Ok(StudyRecord {
+                    study_instance_uid: row.get(0)?,
+                    study_date: row.get(1)?,
+                    patient_id: row.get(2)?,
+                    patient_name: row.get(3)?,
+                    uploaded_instances: row.get::<_, i64>(4)? as usize,
+                    failed_instances: row.get::<_, i64>(5)? as usize,
+                    recipients: recipients
+                        .map(|r| r.split(',').map(str::to_string).collect())
+                        .unwrap_or_default(),
+                    created_at: row.get::<_, i64>(7)? as u64,
+                    updated_at: row.get::<_, i64>(8)? as u64,
+                })
+            })
+            .whatever_context("could not query study history")?;
+        rows.collect::<Result<_, _>>().whatever_context("could not read study history")
+    }
+}
+
+/// Encrypts `data` with `key`, prefixed with the random nonce.
+fn seal(data: &[u8], key: &secretbox::Key) -> Vec<u8> {
+    let nonce = secretbox::gen_nonce();
+    let mut encrypted = nonce.0.to_vec();
+    encrypted.extend(secretbox::seal(data, &nonce, key));
+    encrypted
+}
+
+fn unseal(encrypted: &[u8], key: &secretbox::Key) -> Result<Vec<u8>, Whatever> {
+    ensure_whatever!(encrypted.len() > secretbox::NONCEBYTES, "encrypted value is truncated");
+    let (nonce, ciphertext) = encrypted.split_at(secretbox::NONCEBYTES);
+    let nonce = secretbox::Nonce::from_slice(nonce).unwrap();
+    secretbox::open(ciphertext, &nonce, key)
+        .ok()
+        .whatever_context("could not decrypt value")
+}
+
+/// Derives the key the study keys are encrypted with from the database
+/// password and the salt stored in the database, which is generated when
+/// the database is created.
+fn database_key(conn: &mut Connection, db_password: &str) -> Result<secretbox::Key, Whatever> {
+    let tx = conn.transaction().whatever_context("could not read database key settings")?;
+    let setting = |name: &str| -> Result<Option<Vec<u8>>, Whatever> {
+        tx.query_row("SELECT value FROM settings WHERE name = ?1", [name], |row| row.get(0))
+            .optional()
+            .with_whatever_context(|_| format!("could not read setting {}", name))
+    };
+    if let (Some(salt), Some(check)) = (setting("key_salt")?, setting("key_check")?) {
+        let salt = argon2id13::Salt::from_slice(&salt).whatever_context("stored key salt is invalid")?;
+        let key = derive_key(db_password, &salt)?;
+        ensure_whatever!(unseal(&check, &key).ok().as_deref() == Some(KEY_CHECK),
+            "could not unlock the study database, is the database password correct?");
+        return Ok(key);
+    }
+
+    let salt = argon2id13::gen_salt();
+    let key = derive_key(db_password, &salt)?;
+    for (name, value) in [("key_salt", salt.0.to_vec()), ("key_check", seal(KEY_CHECK, &key))] {
+        tx.execute("INSERT INTO settings (name, value) VALUES (?1, ?2)", params![name, value])
+            .with_whatever_context(|_| format!("could not store setting {}", name))?;
+    }
+    tx.commit().whatever_context("could not store database key settings")?;
+    Ok(key)
+}
+
+fn derive_key(db_password: &str, salt: &argon2id13::Salt) -> Result<secretbox::Key, Whatever> {
+    let mut key = secretbox::Key([0; secretbox::KEYBYTES]);
+    argon2id13::derive_key(
+        &mut key.0,
+        db_password.as_bytes(),
+        salt,
+        argon2id13::OPSLIMIT_INTERACTIVE,
+        argon2id13::MEMLIMIT_INTERACTIVE,
+    )
+    .ok()
+    .whatever_context("could not derive database key")?;
+    Ok(key)
+}
+
+/// Runs the migrations newer than the schema version of the database.
+fn migrate(conn: &mut Connection) -> Result<(), Whatever> {
+    let version: usize = conn
+        .pragma_query_value(None, "user_version", |row| row.get::<_, i64>(0))
+        .whatever_context("could not read schema version")? as usize;
+    ensure_whatever!(version <= MIGRATIONS.len(),
+        "study database has schema version {}, this gateway supports up to {}", version, MIGRATIONS.len());
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction().whatever_context("could not start migration")?;
+        tx.execute_batch(migration)
+            .with_whatever_context(|_| format!("could not migrate study database to version {}", index + 1))?;
+        tx.pragma_update(None, "user_version", (index + 1) as i64)
+            .whatever_context("could not update schema version")?;
+        tx.commit().whatever_context("could not commit migration")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema_version(db: &StudyDb) -> usize {
+        db.conn
+            .lock()
+            .unwrap()
+            .pragma_query_value(None, "user_version", |row| row.get::<_, i64>(0))
+            .unwrap() as usize
+    }
+
+    #[test]
+    fn creates_the_latest_schema_and_keeps_study_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = StudyDb::open(dir.path(), "secret").unwrap();
+        assert_eq!(schema_version(&db), MIGRATIONS.len());
+        assert_eq!(db.study_key("1.2.3", "aa").unwrap(), ("aa".to_string(), true));
+        assert_eq!(db.study_key("1.2.3", "bb").unwrap(), ("aa".to_string(), false));
+        drop(db);
+
+        let db = StudyDb::open(dir.path(), "secret").unwrap();
+        assert_eq!(db.study_key("1.2.3", "cc").unwrap(), ("aa".to_string(), false));
+        drop(db);
+        assert!(StudyDb::open(dir.path(), "wrong").is_err());
+    }
+
+    #[test]
+    fn records_uploads_and_shares() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = StudyDb::open(dir.path(), "secret").unwrap();
+        db.study_key("1.2.3", "aa").unwrap();
+        let instance = StoredInstance {
+            study_instance_uid: "1.2.3".to_string(),
+            sop_class_uid: "1.2.840.10008.5.1.4.1.1.2".to_string(),
+            sop_instance_uid: "1.2.3.4.1".to_string(),
+            series_instance_uid: "1.2.3.4".to_string(),
+            transfer_syntax_uid: "1.2.840.10008.1.2.1".to_string(),
+            calling_ae_title: "CT01".to_string(),
+            file: "1.2.3.4.1".into(),
+        };
+        db.record_upload("1.2.3", &instance, None, UploadStatus::Failed, Some("unreachable")).unwrap();
+        let history = db.history(10).unwrap();
+        assert_eq!((history[0].uploaded_instances, history[0].failed_instances), (0, 1));
+
+        db.record_upload("1.2.3", &instance, None, UploadStatus::Uploaded, None).unwrap();
+        let mut study = Study {
+            study_instance_uid: "1.2.3".to_string(),
+            study_instance_uid_hash: "hash".to_string(),
+            study_date: "20240101".to_string(),
+            patient_id: "P1".to_string(),
+            patient_name: "Doe^Jane".to_string(),
+            patient_birth_date: "19700101".to_string(),
+            new_study: true,
+            hex_key: "aa".to_string(),
+            series_instance_uids: ["1.2.3.4".to_string()].into(),
+            instance_count: 1,
+            instances: vec![],
+            failed_instances: vec![],
+        };
+        db.record_share(&study, &["!room:example.org".to_string()]).unwrap();
+        study.instance_count = 2;
+        db.record_share(&study, &["!room:example.org".to_string()]).unwrap();
+        assert_eq!(db.shared_with("1.2.3", "!room:example.org").unwrap(), Some(2));
+        assert_eq!(db.shared_with("1.2.3", "!other:example.org").unwrap(), None);
+
+        let history = db.history(10).unwrap();
+        assert_eq!((history[0].uploaded_instances, history[0].failed_instances), (1, 0));
+        assert_eq!(history[0].recipients, vec!["!room:example.org".to_string()]);
+    }
+}
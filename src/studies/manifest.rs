@@ -0,0 +1,151 @@
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use snafu::{ensure_whatever, ResultExt, Whatever};
+use sodiumoxide::crypto::secretbox;
+
+use crate::studies::crypto::{decrypt_instance, encrypt_stream};
+use crate::studies::store::Study;
+
+const MANIFEST_VERSION: u32 = 1;
+
+/// Object key of the manifest of the study stored below `study_hash`.
+pub fn manifest_key(study_hash: &str) -> String {
+    format!("{}/manifest", study_hash)
+}
+
+/// Encrypted index of a shared study, uploaded after its instances so the
+/// recipients can check that the study is complete before importing it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Manifest {
+    pub version: u32,
+    pub study_instance_uid: String,
+    pub study_date: String,
+    pub patient_id: String,
+    pub patient_name: String,
+    pub patient_birth_date: String,
+    /// AE title of the gateway which shared the study
+    pub gateway: String,
+    /// Seconds since the Unix epoch of the last update
+    pub created_at: u64,
+    pub instances: Vec<ManifestEntry>,
+}
+
+/// An encrypted instance of a shared study.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ManifestEntry {
+    pub object_key: String,
+    pub sop_class_uid: String,
+    pub sop_instance_uid: String,
+    pub series_instance_uid: String,
+    pub transfer_syntax_uid: String,
+    /// Size of the plaintext instance
+    pub size: u64,
+    /// Hex encoded SHA-256 of the plaintext instance
+    pub sha256: String,
+    /// Calling AE title of the modality or PACS which sent the instance
+    pub sender_ae_title: String,
+    /// Seconds since the Unix epoch the instance was received
+    pub received_at: u64,
+}
+
+impl Manifest {
+    pub fn new(study: &Study, gateway: &str) -> Manifest {
+        Manifest {
+            version: MANIFEST_VERSION,
+            study_instance_uid: study.study_instance_uid.clone(),
+            study_date: study.study_date.clone(),
+            patient_id: study.patient_id.clone(),
+            patient_name: study.patient_name.clone(),
+            patient_birth_date: study.patient_birth_date.clone(),
+            gateway: gateway.to_string(),
+            created_at: unix_time(),
+            instances: vec![],
+        }
+    }
+
+    /// Adds `instances`, replacing entries of instances which were sent again.
+    pub fn merge(&mut self, instances: &[ManifestEntry]) {
+        for instance in instances {
+            self.instances.retain(|i| i.sop_instance_uid != instance.sop_instance_uid);
+            self.instances.push(instance.clone());
+        }
+        self.created_at = unix_time();
+    }
+
+    pub fn series_count(&self) -> usize {
+        self.instances.iter().map(|i| &i.series_instance_uid).collect::<BTreeSet<_>>().len()
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.instances.iter().map(|i| i.size).sum()
+    }
+
+    pub fn senders(&self) -> BTreeSet<&str> {
+        self.instances.iter().map(|i| i.sender_ae_title.as_str()).collect()
+    }
+
+    pub fn encrypt(&self, key: &secretbox::Key) -> Result<Vec<u8>, Whatever> {
+        let json = serde_json::to_vec(self).whatever_context("could not serialize manifest")?;
+        let mut encrypted = vec![];
+        encrypt_stream(json.as_slice(), &mut encrypted, key).whatever_context("could not encrypt manifest")?;
+        Ok(encrypted)
+    }
+
+    pub fn decrypt(encrypted: &[u8], key: &secretbox::Key) -> Result<Manifest, Whatever> {
+        let json = decrypt_instance(encrypted, key).whatever_context("could not decrypt manifest")?;
+        let manifest: Manifest = serde_json::from_slice(&json).whatever_context("could not parse manifest")?;
+        ensure_whatever!(manifest.version == MANIFEST_VERSION, "unsupported manifest version {}", manifest.version);
+        Ok(manifest)
+    }
+}
+
+/// Computes the size and SHA-256 of the data read from or written to the
+/// wrapped stream.
+pub struct Digesting<T> {
+    inner: T,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<T> Digesting<T> {
+    pub fn new(inner: T) -> Digesting<T> {
+        Digesting { inner, hasher: Sha256::new(), size: 0 }
+    }
+
+    /// Returns the stream, the hex encoded SHA-256 and the size.
+    pub fn finish(self) -> (T, String, u64) {
+        (self.inner, format!("{:x}", self.hasher.finalize()), self.size)
+    }
+}
+
+impl<R: Read> Read for Digesting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.size += n as u64;
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for Digesting<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
@@ -1 +1,4 @@
+pub mod crypto;
+pub mod db;
+pub mod manifest;
 pub mod store;
\ No newline at end of file
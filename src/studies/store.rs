@@ -1,36 +1,34 @@
 use std::fs;
-use dicom_core::{dicom_value, DataElement, PrimitiveValue, VR, Tag};
+use dicom_core::Tag;
 use dicom_dictionary_std::tags;
-use dicom_dictionary_std::tags::{STUDY_INSTANCE_UID, STUDY_DATE, PATIENT_ID, PATIENT_NAME, PATIENT_BIRTH_DATE, PATIENT_BIRTH_NAME};
-use dicom_encoding::transfer_syntax::TransferSyntaxIndex;
-use dicom_object::{DefaultDicomObject, FileMetaTableBuilder, InMemDicomObject, StandardDataDictionary};
-use dicom_transfer_syntax_registry::TransferSyntaxRegistry;
-use dicom_ul::{pdu::PDataValueType, Pdu};
-use dicom_object::FileDicomObject;
+use dicom_dictionary_std::tags::{STUDY_INSTANCE_UID, SERIES_INSTANCE_UID, STUDY_DATE, PATIENT_ID, PATIENT_NAME, PATIENT_BIRTH_DATE};
+use dicom_object::{DefaultDicomObject, OpenFileOptions};
 use sodiumoxide::crypto::secretbox;
-use std::collections::HashMap;
+use std::collections::BTreeSet;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{self, BufReader, BufWriter};
 use std::path::PathBuf;
-use std::str::FromStr;
-use dicom_object::open_file;
-use microkv::MicroKV;
-use snafu::{ResultExt, Whatever};
+use serde_derive::{Deserialize, Serialize};
+use snafu::{whatever, OptionExt, ResultExt, Whatever};
 use sha2::{Sha256, Digest};
-use uuid::Uuid;
 use std::path::Path;
-use dicom_encoding::DataRWAdapter;
-use s3::{Bucket, Region};
-use s3::creds::Credentials;
-use s3::error::S3Error;
-use tokio::runtime::Runtime;
+use std::sync::Arc;
+use crate::dcm::storescp::StoredInstance;
+use crate::studies::db::{StudyDb, UploadStatus};
+use crate::studies::crypto::{decrypt_stream, encrypt_stream, parse_key};
+use crate::studies::manifest::{manifest_key, unix_time, Digesting, Manifest, ManifestEntry};
+use crate::storage::object_store::{self, ObjectStore};
+use tracing::error;
 
+#[derive(Clone)]
 pub struct StudyStore {
     pub run_dir: PathBuf,
-    pub db: MicroKV,
+    pub db: StudyDb,
+    pub object_store: Arc<dyn ObjectStore>,
+    pub keep_received_files: bool,
 }
 
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Study {
     pub study_instance_uid:String,
     pub study_instance_uid_hash:String,
@@ -40,67 +38,73 @@ pub struct Study {
     pub patient_birth_date:String,
     pub new_study: bool,
     pub hex_key: String,
+    pub series_instance_uids: BTreeSet<String>,
+    pub instance_count: usize,
+    /// Manifest entries of the instances uploaded for this study
+    pub instances: Vec<ManifestEntry>,
+    /// SOP instance UIDs of the instances which could not be uploaded
+    pub failed_instances: Vec<String>,
 }
 
 impl Study {
-    fn new(db: &MicroKV) -> StudyBuilder {
+    fn new(db: &StudyDb) -> StudyBuilder {
         StudyBuilder::new(db.clone())
         }
+
+    pub fn series_count(&self) -> usize {
+        self.series_instance_uids.len()
+    }
+
+    /// Adds the instances and series of `other`, a later part of the same study.
+    pub fn merge(&mut self, other: Study) {
+        self.series_instance_uids.extend(other.series_instance_uids);
+        self.instance_count += other.instance_count;
+        self.instances.extend(other.instances);
+        self.failed_instances.extend(other.failed_instances);
+        self.new_study |= other.new_study;
+    }
     }
 
 
 struct StudyBuilder {
-    db: MicroKV,
+    db: StudyDb,
     study_instance_uid: Option<String>,
     study_instance_uid_hash: Option<String>,
     study_date:String,
     patient_id:String,
     patient_name:String,
     patient_birth_date:String,
+    series_instance_uid: Option<String>,
     hex_key : Option<String>,
     new_study: bool
 }
 
 impl StudyBuilder {
 
-    pub fn new(db: MicroKV) -> StudyBuilder {
+    pub fn new(db: StudyDb) -> StudyBuilder {
         StudyBuilder {
-            db: db,
+            db,
             study_instance_uid:  None,
             study_instance_uid_hash: None,
             study_date: "N/A".to_string(),
             patient_id: "N/A".to_string(),
             patient_name: "N/A".to_string(),
             patient_birth_date: "N/A".to_string(),
+            series_instance_uid: None,
             hex_key: None,
             new_study: false
         }
     }
 
-    fn study_instance_uid(mut self, uid: String) -> StudyBuilder {
-        self.study_instance_uid = Some(uid);
-        let hex_key: String = match self.db.exists(self.study_instance_uid.clone().unwrap()) {
-            Ok(v) => {
-                if !v {
-                    let key = secretbox::gen_key();
-                    let hex_key = hex::encode(&key);
-                    self.new_study = true;
-                    hex_key
-                } else {
-                    self.db.get_unwrap(self.study_instance_uid.clone().unwrap()).unwrap()
-                }
-            },
-            Err(v) => {
-                println!("Something went wrong !");
-                stringify!("").to_string()
-            }
-            ,
-        };
-        self.hex_key = Some(hex_key);
+    fn study_instance_uid(mut self, uid: String) -> Result<StudyBuilder, Whatever> {
+        let (hex_key, new_study) = self.db.study_key(&uid, &hex::encode(secretbox::gen_key()))?;
         let mut hasher = Sha256::new();
-        hasher.update(format!("{}_{}", self.hex_key.as_ref().unwrap(), &self.study_instance_uid.as_ref().unwrap()));
+        hasher.update(format!("{}_{}", hex_key, uid));
         self.study_instance_uid_hash = Some(format!("{:x}",hasher.finalize()));
-        self
+        self.study_instance_uid = Some(uid);
+        self.new_study = new_study;
+        self.hex_key = Some(hex_key);
+        Ok(self)
     }
 
     fn study_date(mut self, study_date: String) -> StudyBuilder {
@@ -119,109 +123,210 @@ impl StudyBuilder {
         self.patient_birth_date = patient_birth_date;
         self
     }
-    fn from_dicom_object(self,obj: &DefaultDicomObject) -> StudyBuilder {
-        let study_instance_uid = get_tag(&obj,STUDY_INSTANCE_UID);
-        let study_date = get_tag(&obj,STUDY_DATE);
-        let patient_id = get_tag(&obj,PATIENT_ID);
-        let patient_name = get_tag(&obj,PATIENT_NAME);
-        let patient_birth_data = get_tag(&obj,PATIENT_BIRTH_DATE);
-        self.study_instance_uid(study_instance_uid)
+    fn series_instance_uid(mut self, series_instance_uid: String) -> StudyBuilder {
+        self.series_instance_uid = Some(series_instance_uid);
+        self
+    }
+    /// Takes the study from `obj`, which must have a Study Instance UID.
+    /// Missing patient and study attributes are left empty.
+    fn from_dicom_object(self,obj: &DefaultDicomObject) -> Result<StudyBuilder, Whatever> {
+        let study_instance_uid = get_tag(obj, STUDY_INSTANCE_UID)?
+            .filter(|uid| !uid.is_empty())
+            .whatever_context("missing Study Instance UID")?;
+        let study_date = get_tag(obj, STUDY_DATE)?.unwrap_or_default();
+        let patient_id = get_tag(obj, PATIENT_ID)?.unwrap_or_default();
+        let patient_name = get_tag(obj, PATIENT_NAME)?.unwrap_or_default();
+        let patient_birth_data = get_tag(obj, PATIENT_BIRTH_DATE)?.unwrap_or_default();
+        let mut builder = self.study_instance_uid(study_instance_uid)?
         .study_date(study_date)
         .patient_id(patient_id)
         .patient_name(patient_name)
-        .patient_birth_date(patient_birth_data)
+        .patient_birth_date(patient_birth_data);
+        if let Some(series_instance_uid) = get_tag(obj, SERIES_INSTANCE_UID)? {
+            builder = builder.series_instance_uid(series_instance_uid);
+        }
+        Ok(builder)
     }
-    fn from_dicom_file(self,dicom_file: &PathBuf) -> StudyBuilder {
-        let obj = open_file(&dicom_file).unwrap();
+    /// Reads the attributes before the pixel data of `dicom_file`.
+    fn from_dicom_file(self,dicom_file: &Path) -> Result<StudyBuilder, Whatever> {
+        let obj = OpenFileOptions::new()
+            .read_until(tags::PIXEL_DATA)
+            .open_file(dicom_file)
+            .with_whatever_context(|_| format!("could not open {}", dicom_file.display()))?;
         self.from_dicom_object(&obj)
     }
 
-    fn build(self) -> Study {
-        if self.new_study {
-            self.db.put(self.study_instance_uid.as_ref().unwrap(), self.hex_key.as_ref().unwrap());
-        }
-        Study {
-            study_instance_uid: self.study_instance_uid.unwrap().to_string(),
-            study_instance_uid_hash: self.study_instance_uid_hash.unwrap(),
+    fn build(self) -> Result<Study, Whatever> {
+        let (Some(study_instance_uid), Some(study_instance_uid_hash), Some(hex_key)) =
+            (self.study_instance_uid, self.study_instance_uid_hash, self.hex_key) else {
+            whatever!("study has no Study Instance UID");
+        };
+        Ok(Study {
+            study_instance_uid,
+            study_instance_uid_hash,
             study_date: self.study_date,
             patient_id: self.patient_id,
             patient_name: self.patient_name,
             patient_birth_date: self.patient_birth_date,
-            hex_key: self.hex_key.unwrap(),
-            new_study: self.new_study
-        }
+            hex_key,
+            new_study: self.new_study,
+            series_instance_uids: self.series_instance_uid.into_iter().collect(),
+            instance_count: 1,
+            instances: vec![],
+            failed_instances: vec![],
+        })
     }
 }
 
-fn get_tag(obj: &DefaultDicomObject, tag: Tag) -> String {
-    obj.element(tag)
-        .unwrap()
+/// Encrypts `dicom_file` into `encrypted_file`, returns the SHA-256 and size
+/// of the plaintext.
+fn encrypt_file(dicom_file: &Path, encrypted_file: &Path, key: &secretbox::Key) -> Result<(String, u64), Whatever> {
+    let input_file = File::open(dicom_file)
+        .with_whatever_context(|_| format!("could not open {}", dicom_file.display()))?;
+    let mut input = Digesting::new(BufReader::new(input_file));
+    let partial_file = encrypted_file.with_extension("partial");
+    let output_file = File::create(&partial_file)
+        .with_whatever_context(|_| format!("could not create {}", partial_file.display()))?;
+    encrypt_stream(&mut input, BufWriter::new(output_file), key)
+        .with_whatever_context(|_| format!("could not encrypt {}", dicom_file.display()))?;
+    fs::rename(&partial_file, encrypted_file)
+        .with_whatever_context(|_| format!("could not create {}", encrypted_file.display()))?;
+    let (_, sha256, size) = input.finish();
+    Ok((sha256, size))
+}
+
+/// SHA-256 and size of `dicom_file` if `encrypted_file` exists and holds
+/// the same instance.
+fn resumable_digest(encrypted_file: &Path, dicom_file: &Path, key: &secretbox::Key) -> Option<(String, u64)> {
+    let encrypted = File::open(encrypted_file).ok()?;
+    let size = fs::metadata(dicom_file).ok()?.len();
+    let mut decrypted = Digesting::new(io::sink());
+    decrypt_stream(BufReader::new(encrypted), &mut decrypted, key, Some(size)).ok()?;
+    let (_, encrypted_sha256, _) = decrypted.finish();
+    let mut input = Digesting::new(BufReader::new(File::open(dicom_file).ok()?));
+    io::copy(&mut input, &mut io::sink()).ok()?;
+    let (_, sha256, size) = input.finish();
+    (sha256 == encrypted_sha256).then_some((sha256, size))
+}
+
+/// Value of the attribute `tag`, `None` if `obj` does not have it.
+fn get_tag(obj: &DefaultDicomObject, tag: Tag) -> Result<Option<String>, Whatever> {
+    let Some(element) = obj.element_opt(tag).with_whatever_context(|_| format!("could not read attribute {}", tag))? else {
+        return Ok(None);
+    };
+    let value = element
         .to_str()
-        .unwrap().to_string()
+        .with_whatever_context(|_| format!("attribute {} is not a string", tag))?;
+    Ok(Some(value.to_string()))
+}
+
+/// An instance encrypted into the run directory, ready to be uploaded.
+struct EncryptedInstance {
+    study: Study,
+    encrypted_file: PathBuf,
+    file_id: String,
+    sha256: String,
+    size: u64,
+}
+
+/// Records the study of `instance` and encrypts it, reusing an encrypted
+/// file left by a failed upload so the upload can resume.
+fn encrypt_instance(db: &StudyDb, run_dir: &Path, instance: &StoredInstance) -> Result<EncryptedInstance, Whatever> {
+    let dicom_file = &instance.file;
+    let study = Study::new(db).from_dicom_file(dicom_file)?.build()?;
+    db.update_study(&study)?;
+    let key = parse_key(&study.hex_key).whatever_context("invalid study key")?;
+    let mut hasher = Sha256::new();
+    hasher.update(format!("{}_{}", &study.hex_key, &instance.sop_instance_uid));
+    let file_id = format!("{:x}",hasher.finalize());
+    let encrypted_file = run_dir.join(&file_id);
+    let (sha256, size) = match resumable_digest(&encrypted_file, dicom_file, &key) {
+        Some(digest) => digest,
+        None => encrypt_file(dicom_file, &encrypted_file, &key)?,
+    };
+    Ok(EncryptedInstance { study, encrypted_file, file_id, sha256, size })
 }
 
 impl StudyStore {
 
-async fn upload_series(self,file_path: &PathBuf, object_name: &String) -> Result<(), S3Error>{
-        let bucket = Bucket::new_with_path_style("studies",Region::Custom {region:"".to_owned(),endpoint: "https://nonelabs.com:9090".to_owned()}, Credentials{
-            access_key: Some("minio".to_owned()),
-            secret_key: Some("f381hf1h2g70hvq23ubgiu123r".to_owned()),
-            expiration: None,
-            security_token: None,
-            session_token: None,
-        });
-        let file = fs::read(Path::new(&file_path))?;
-        match bucket.unwrap().put_object(&object_name, file.as_slice()).await{
-            Ok(T) => println!("file uploaded"),
-            Err(E) => println!("Something {}",E)
-        }
-        println!("File uploaded successfully.");
-        Ok(())
+pub fn open(run_dir: &Path, db_dir: &Path, db_password: &str, object_store: Arc<dyn ObjectStore>, keep_received_files: bool) -> Result<StudyStore, Whatever> {
+        let db = StudyDb::open(db_dir, db_password)?;
+        Ok(StudyStore {
+            run_dir: run_dir.to_path_buf(),
+            db,
+            object_store,
+            keep_received_files,
+        })
     }
 
-pub async fn add_series(self, sop_instance_uid: &String) -> Result<Study, Whatever> {
+async fn upload_series(&self, file_path: &Path, object_name: &str) -> Result<(), object_store::Error> {
+        self.object_store.put_file(object_name, file_path).await
+    }
+
+    /// Encrypts and uploads `instance`. If the upload fails the instance is
+    /// recorded in [`Study::failed_instances`] and its files are kept, so
+    /// the next attempt continues the interrupted upload.
+pub async fn add_series(&self, instance: &StoredInstance) -> Result<Study, Whatever> {
         sodiumoxide::init();
-        let mut dicom_file = self.run_dir.clone();
-        dicom_file.push(&sop_instance_uid.trim_end_matches('\0').to_string());
-        let study = Study::new(&self.db).from_dicom_file(&dicom_file).build();
-        let key_bytes = hex::decode(&study.hex_key).expect("Invalid hex string");
-        let key = secretbox::Key::from_slice(&key_bytes).expect("Invalid key");
-        let mut encrypted_file = self.run_dir.clone();
-        let mut hasher = Sha256::new();
-        hasher.update(format!("{}_{}", &study.hex_key, &sop_instance_uid.trim_end_matches('\0').to_string()));
-        let file_id = format!("{:x}",hasher.finalize());
-        encrypted_file.push(PathBuf::from(&file_id));
-        let mut input_file = File::open(&dicom_file).unwrap();
-        let mut input_data = Vec::new();
-        input_file.read_to_end(&mut input_data).unwrap();
-        let nonce = secretbox::gen_nonce();
-        let encrypted_data = secretbox::seal(&input_data, &nonce, &key);
-        let mut output_file = File::create(&encrypted_file).unwrap();
-        output_file.write_all(&nonce.0).unwrap();
-        output_file.write_all(&encrypted_data);
+        let dicom_file = &instance.file;
+        let (db, run_dir, blocking_instance) = (self.db.clone(), self.run_dir.clone(), instance.clone());
+        // reading, hashing and encrypting block, Whatever is not Send
+        let encrypted = tokio::task::spawn_blocking(move || {
+            encrypt_instance(&db, &run_dir, &blocking_instance).map_err(|e| snafu::Report::from_error(e).to_string())
+        })
+        .await;
+        let EncryptedInstance { mut study, encrypted_file, file_id, sha256, size } = match encrypted {
+            Ok(Ok(encrypted)) => encrypted,
+            Ok(Err(e)) => whatever!("{}", e),
+            Err(e) => whatever!("encryption of instance {} aborted: {}", instance.sop_instance_uid, e),
+        };
         let object_name = format!("{}/{}",&study.study_instance_uid_hash,&file_id);
-        self.upload_series(&encrypted_file, &object_name).await;
-        fs::remove_file(encrypted_file);
-        fs::remove_file(dicom_file);
+        if let Err(e) = self.upload_series(&encrypted_file, &object_name).await {
+            let e = snafu::Report::from_error(e).to_string();
+            error!("Could not upload instance {}: {}", instance.sop_instance_uid, e);
+            self.db.record_upload(&study.study_instance_uid, instance, None, UploadStatus::Failed, Some(&e))?;
+            study.failed_instances.push(instance.sop_instance_uid.clone());
+            return Ok(study);
+        }
+        let entry = ManifestEntry {
+            object_key: object_name,
+            sop_class_uid: instance.sop_class_uid.clone(),
+            sop_instance_uid: instance.sop_instance_uid.clone(),
+            series_instance_uid: instance.series_instance_uid.clone(),
+            transfer_syntax_uid: instance.transfer_syntax_uid.clone(),
+            size,
+            sha256,
+            sender_ae_title: instance.calling_ae_title.clone(),
+            received_at: unix_time(),
+        };
+        self.db.record_upload(&study.study_instance_uid, instance, Some(&entry), UploadStatus::Uploaded, None)?;
+        study.instances.push(entry);
+        let _ = fs::remove_file(encrypted_file);
+        if !self.keep_received_files {
+            let _ = fs::remove_file(dicom_file);
+        }
         Ok(study)
     }
-}
 
-// pub fn decrypt(input_path: &str, output_path: &str, key: &secretbox::Key) -> Result<(), Box<dyn std::error::Error>> {
-//     sodiumoxide::init();
-//     let mut input_file = File::open(input_path)?;
-//     let mut nonce_encrypted_data = Vec::new();
-//     input_file.read_to_end(&mut nonce_encrypted_data)?;
-//     let (nonce_bytes, encrypted_data) = nonce_encrypted_data.split_at(secretbox::NONCEBYTES);
-//     let nonce = secretbox::Nonce::from_slice(nonce_bytes).ok_or("Failed to read nonce")?;
-//     let decrypted_data = secretbox::open(&encrypted_data, &nonce, &key);
-//     match decrypted_data{
-//         Ok(T) => {
-//             let mut output_file = File::create(output_path)?;
-//             output_file.write_all(&T);
-//         },
-//         Err(E) => println!("Cannot decrypt"),
-//
-//     }
-//     Ok(())
-// }
\ No newline at end of file
+    /// Uploads the manifest of `study` after all its instances were
+    /// uploaded. Instances shared earlier are kept in the manifest and
+    /// counted in the series and instances of `study`.
+    pub async fn publish_manifest(&self, study: &mut Study, gateway: &str) -> Result<(), Whatever> {
+        let key = parse_key(&study.hex_key).whatever_context("invalid study key")?;
+        let key_name = manifest_key(&study.study_instance_uid_hash);
+        let mut manifest = match self.object_store.get(&key_name).await {
+            Ok(encrypted) => Manifest::decrypt(&encrypted, &key)?,
+            Err(object_store::Error::NotFound { .. }) => Manifest::new(study, gateway),
+            Err(e) => return Err(e).whatever_context("could not fetch previous manifest"),
+        };
+        manifest.merge(&study.instances);
+        let encrypted = manifest.encrypt(&key)?;
+        self.object_store
+            .put(&key_name, &encrypted)
+            .await
+            .whatever_context("could not upload manifest")?;
+        study.series_instance_uids = manifest.instances.iter().map(|i| i.series_instance_uid.clone()).collect();
+        study.instance_count = manifest.instances.len();
+        Ok(())
+    }
+}